@@ -17,10 +17,11 @@ fn main() {
         )
         .expect("could not create host");
 
-    host.connect(&Address(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 12345))), 10, 0)
+    let peer_id = host
+        .connect(&Address(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 12345))), 10, 0)
         .expect("connect failed");
 
-    let mut peer = loop {
+    loop {
         let e = host.service(1000).expect("service failed");
 
         let e = match e {
@@ -31,11 +32,12 @@ fn main() {
         println!("[client] event: {:#?}", e);
 
         match e {
-            Event::Connect(ref p) => {
-                break p.clone();
+            Event::Connect(ref p) if p.id() == peer_id => {
+                break;
             }
-            Event::Disconnect(ref p, r) => {
-                println!("connection NOT successful, peer: {:?}, reason: {}", p, r);
+            Event::Connect(_) => (),
+            Event::Disconnect(ref p, r, kind) => {
+                println!("connection NOT successful, peer: {:?}, reason: {} ({:?})", p, r, kind);
                 std::process::exit(0);
             }
             Event::Receive { .. } => {
@@ -44,6 +46,8 @@ fn main() {
         };
     };
 
+    let mut peer = host.peer_mut(peer_id).expect("peer was reset");
+
     // send a "hello"-like packet
     peer.send_packet(
         Packet::new(b"harro", PacketMode::ReliableSequenced).unwrap(),
@@ -0,0 +1,275 @@
+use std::{
+    collections::VecDeque,
+    future::poll_fn,
+    io,
+    os::unix::io::RawFd,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures_core::Stream;
+use log::error;
+use tokio::{
+    io::unix::AsyncFd,
+    time::{self, Interval, MissedTickBehavior},
+};
+
+use crate::{Address, Error, Host, OwnedEvent, Packet, PeerError, PeerId};
+
+/// Default interval in which an `AsyncHost` services its `Host` when no datagrams arrive.
+const DEFAULT_SERVICE_INTERVAL: Duration = Duration::from_millis(10);
+
+/// An asynchronous wrapper around a `Host`, driven by the tokio reactor. Requires the `tokio` feature.
+///
+/// The socket of the host is registered with the reactor, and the host is serviced (with a timeout of 0)
+/// whenever the socket becomes readable, as well as in a fixed interval so ENet can handle resends and timeouts.
+/// Events are delivered as `OwnedEvent`s through the `Stream` implementation.
+///
+/// ENet receives a bounded number of datagrams per service, so under heavy load some datagrams
+/// may only be handled on the next interval tick.
+pub struct AsyncHost<T> {
+    // declared before `host`, so it is deregistered before `host` closes the socket
+    socket: AsyncFd<RawFd>,
+    host: Host<T>,
+    interval: Interval,
+    queued: VecDeque<OwnedEvent>,
+}
+
+impl<T> AsyncHost<T> {
+    /// Wraps `host`, using the default service interval of 10ms.
+    ///
+    /// # Panics
+    ///
+    /// Panics if not called from within a tokio runtime.
+    pub fn new(host: Host<T>) -> io::Result<AsyncHost<T>> {
+        AsyncHost::with_service_interval(host, DEFAULT_SERVICE_INTERVAL)
+    }
+
+    /// Wraps `host`, servicing it at least every `service_interval`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if not called from within a tokio runtime, or if `service_interval` is zero.
+    pub fn with_service_interval(host: Host<T>, service_interval: Duration) -> io::Result<AsyncHost<T>> {
+        let socket = AsyncFd::new(host.raw_socket())?;
+
+        let mut interval = time::interval(service_interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        Ok(AsyncHost {
+            socket,
+            host,
+            interval,
+            queued: VecDeque::new(),
+        })
+    }
+
+    /// Returns a reference to the wrapped `Host`.
+    pub fn host(&self) -> &Host<T> {
+        &self.host
+    }
+
+    /// Returns a mutable reference to the wrapped `Host`.
+    ///
+    /// Events returned by servicing the host directly are not delivered through the `Stream`.
+    pub fn host_mut(&mut self) -> &mut Host<T> {
+        &mut self.host
+    }
+
+    /// Unwraps the `Host`, deregistering its socket from the reactor.
+    ///
+    /// Events that were received but not yet taken from the `Stream` are lost.
+    pub fn into_inner(self) -> Host<T> {
+        let AsyncHost { socket, host, .. } = self;
+        drop(socket);
+        host
+    }
+
+    /// Queues `packet` to be sent to the peer `peer` on channel `channel_id`, and flushes the host.
+    ///
+    /// Completes right away, as ENet queues the packet without waiting for the peer or the socket. It is `async`
+    /// nonetheless, like `connect` and `disconnect`, so it can wait in the future (e.g. for a full send window)
+    /// without changing the API.
+    pub async fn send(&mut self, peer: PeerId, channel_id: u8, packet: Packet) -> Result<(), PeerError> {
+        self.host
+            .peer_mut(peer)
+            .ok_or(PeerError::Gone(peer))?
+            .send_packet(packet, channel_id)?;
+
+        self.host.flush();
+
+        Ok(())
+    }
+
+    /// Connects to `address` and waits until the connection is established.
+    ///
+    /// The `Connect` event is still delivered through the `Stream`, as are all events received in the meantime.
+    /// Returns `PeerError::Gone` if the connection attempt failed.
+    pub async fn connect(&mut self, address: &Address, channel_count: usize, user_data: u32) -> Result<PeerId, PeerError> {
+        let peer = self
+            .host
+            .connect(address, channel_count, user_data)
+            .map_err(PeerError::Enet)?;
+        self.host.flush();
+
+        if self.wait_for(peer).await? {
+            Ok(peer)
+        } else {
+            Err(PeerError::Gone(peer))
+        }
+    }
+
+    /// Disconnects from the peer `peer` and waits until the disconnection is complete.
+    ///
+    /// The `Disconnect` event is still delivered through the `Stream`, as are all events received in the meantime.
+    pub async fn disconnect(&mut self, peer: PeerId, user_data: u32) -> Result<(), PeerError> {
+        self.host
+            .peer_mut(peer)
+            .ok_or(PeerError::Gone(peer))?
+            .disconnect(user_data);
+        self.host.flush();
+
+        while self.wait_for(peer).await? {}
+
+        Ok(())
+    }
+
+    /// Services the host until a `Connect` or `Disconnect` event for `peer` arrives,
+    /// returning whether it was a `Connect` event.
+    ///
+    /// All events, including the one for `peer`, are queued for the `Stream`,
+    /// so nothing is lost if the returned future is dropped.
+    async fn wait_for(&mut self, peer: PeerId) -> Result<bool, PeerError> {
+        loop {
+            let event = poll_fn(|cx| self.poll_service(cx)).await.map_err(PeerError::Enet)?;
+
+            let connected = match event {
+                OwnedEvent::Connect { .. } if event.peer_id() == peer => Some(true),
+                OwnedEvent::Disconnect { .. } if event.peer_id() == peer => Some(false),
+                _ => None,
+            };
+
+            self.queued.push_back(event);
+
+            if let Some(connected) = connected {
+                return Ok(connected);
+            }
+        }
+    }
+
+    /// Services the host until it returns an event, waiting for the socket or the interval in between.
+    fn poll_service(&mut self, cx: &mut Context<'_>) -> Poll<Result<OwnedEvent, Error>> {
+        loop {
+            match self.host.service(0) {
+                Ok(Some(event)) => return Poll::Ready(Ok(event.into_owned())),
+                Ok(None) => (),
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+
+            // Readiness is cleared only after servicing, so datagrams arriving in between are not missed.
+            let readable = match self.socket.poll_read_ready(cx) {
+                Poll::Ready(Ok(mut guard)) => {
+                    guard.clear_ready();
+                    true
+                }
+                Poll::Ready(Err(err)) => {
+                    error!("AsyncHost: polling the socket failed: {}", err);
+                    return Poll::Ready(Err(Error(err.raw_os_error().unwrap_or(-1))));
+                }
+                Poll::Pending => false,
+            };
+
+            let ticked = self.interval.poll_tick(cx).is_ready();
+
+            if !readable && !ticked {
+                return Poll::Pending;
+            }
+        }
+    }
+}
+
+impl<T> Stream for AsyncHost<T> {
+    type Item = Result<OwnedEvent, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if let Some(event) = this.queued.pop_front() {
+            return Poll::Ready(Some(Ok(event)));
+        }
+
+        this.poll_service(cx).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{future::poll_fn, pin::Pin};
+
+    use futures_core::Stream;
+    use tokio::{runtime, task::LocalSet};
+
+    use super::AsyncHost;
+    use crate::{
+        tests::{localhost, ENET},
+        OwnedEvent, Packet, PacketMode,
+    };
+
+    async fn next_event<T>(host: &mut AsyncHost<T>) -> OwnedEvent {
+        poll_fn(|cx| Pin::new(&mut *host).poll_next(cx))
+            .await
+            .expect("the stream never ends")
+            .unwrap()
+    }
+
+    #[test]
+    fn test_async_hosts() {
+        let runtime = runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let address = localhost(12385);
+
+        LocalSet::new().block_on(&runtime, async {
+            let server = ENET.host_builder().address(address).build::<()>().unwrap();
+            let mut server = AsyncHost::new(server).unwrap();
+
+            // echoes every packet back until the client disconnects
+            let echo = tokio::task::spawn_local(async move {
+                loop {
+                    match next_event(&mut server).await {
+                        OwnedEvent::Connect { data, .. } => assert_eq!(data, 7),
+                        OwnedEvent::Receive { peer, channel_id, packet, .. } => {
+                            let reply = Packet::new(packet.data(), PacketMode::ReliableSequenced).unwrap();
+                            server.send(peer, channel_id, reply).await.unwrap();
+                        }
+                        OwnedEvent::Disconnect { data, .. } => return data,
+                    }
+                }
+            });
+
+            let client = ENET.host_builder().build::<()>().unwrap();
+            let mut client = AsyncHost::new(client).unwrap();
+
+            let peer = client.connect(&address, 2, 7).await.unwrap();
+            match next_event(&mut client).await {
+                OwnedEvent::Connect { peer: connected, .. } => assert_eq!(connected, peer),
+                event => panic!("unexpected event {:?}", event),
+            }
+
+            client
+                .send(peer, 1, Packet::new(b"ping", PacketMode::ReliableSequenced).unwrap())
+                .await
+                .unwrap();
+            match next_event(&mut client).await {
+                OwnedEvent::Receive { channel_id, packet, .. } => {
+                    assert_eq!(channel_id, 1);
+                    assert_eq!(packet.data(), b"ping");
+                }
+                event => panic!("unexpected event {:?}", event),
+            }
+
+            client.disconnect(peer, 9).await.unwrap();
+            assert!(matches!(next_event(&mut client).await, OwnedEvent::Disconnect { .. }));
+            assert_eq!(echo.await.unwrap(), 9);
+        });
+    }
+}
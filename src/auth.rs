@@ -0,0 +1,544 @@
+use std::{
+    collections::VecDeque,
+    fmt, ptr, slice,
+    time::{Duration, Instant},
+};
+
+use hmac::{Hmac, Mac};
+use log::debug;
+use sha2::Sha256;
+
+use citizen_enet_sys::{
+    enet_packet_create, enet_packet_destroy, enet_peer_disconnect, enet_peer_disconnect_now, enet_peer_send,
+    ENetEvent, ENetHost, ENetPacket, ENetPeer, _ENetEventType_ENET_EVENT_TYPE_CONNECT,
+    _ENetEventType_ENET_EVENT_TYPE_DISCONNECT, _ENetEventType_ENET_EVENT_TYPE_RECEIVE,
+    _ENetPacketFlag_ENET_PACKET_FLAG_NO_ALLOCATE,
+};
+
+use crate::{Packet, PacketMode};
+
+/// The user data of the disconnection of a peer that failed to authenticate, see `Host::set_authentication`.
+pub const AUTHENTICATION_FAILED: u32 = 0x4155_5448;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+const CHALLENGE_LEN: usize = 32;
+const MAC_LEN: usize = 32;
+// binds the MACs to this protocol, in case the key is also used elsewhere
+const MAC_CONTEXT: &[u8] = b"citizen-enet authentication v1";
+
+const MESSAGE_CHALLENGE: u8 = 1;
+const MESSAGE_RESPONSE: u8 = 2;
+const MESSAGE_ACCEPT: u8 = 3;
+
+/// The number of packets held back per peer until it authenticated, beyond which it fails to authenticate.
+const MAX_HELD_PACKETS: usize = 256;
+
+/// Authenticates the peers connecting to a `Host` with a pre-shared key, see `Host::set_authentication`.
+///
+/// The accepting host sends each new peer a random challenge on a reserved channel, which the connecting host
+/// answers with an HMAC-SHA256 of the challenge, keyed with the pre-shared key. Only the connecting host ever
+/// computes an answer, so a challenge cannot be reflected to obtain its answer from the accepting host.
+/// Both hosts have to use the same key and channel.
+#[derive(Clone)]
+pub struct Authentication {
+    key: Vec<u8>,
+    channel_id: u8,
+    timeout: Duration,
+}
+
+impl Authentication {
+    /// Creates an authentication with the pre-shared `key`, exchanging its messages on channel `channel_id`.
+    ///
+    /// The channel is reserved for the authentication, so peers have to be connected with enough channels
+    /// to include it, and packets the application sends on it are discarded by the receiving host.
+    pub fn new(key: &[u8], channel_id: u8) -> Self {
+        Authentication {
+            key: key.to_vec(),
+            channel_id,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Sets how long a connected peer may take to authenticate, 5 seconds by default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Returns the channel reserved for the authentication.
+    pub fn channel_id(&self) -> u8 {
+        self.channel_id
+    }
+
+    fn mac(&self, connect_id: u32, challenge: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        mac.update(MAC_CONTEXT);
+        mac.update(&connect_id.to_be_bytes());
+        mac.update(challenge);
+        mac
+    }
+}
+
+impl fmt::Debug for Authentication {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // never print the key
+        f.debug_struct("Authentication")
+            .field("channel_id", &self.channel_id)
+            .field("timeout", &self.timeout)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug)]
+enum Role {
+    /// The connection was initiated by `Host::connect`, and waits for a challenge and its acceptance.
+    Initiator,
+    /// The connection was accepted, and waits for the answer to `challenge`.
+    Responder { challenge: [u8; CHALLENGE_LEN] },
+}
+
+/// A packet received from a pending peer, to be delivered after its `Connect` event.
+#[derive(Debug)]
+struct Held {
+    channel_id: u8,
+    flags: u32,
+    data: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct Pending {
+    connect_id: u32,
+    role: Role,
+    // `None` once an initiator gave up, while its disconnection is in progress
+    deadline: Option<Instant>,
+    held: Vec<Held>,
+}
+
+/// The state of a `Host`'s `Authentication`, which holds back the events of peers until they are authenticated,
+/// and keeps the application from sending packets to them.
+#[derive(Debug)]
+pub(crate) struct Authenticator {
+    config: Authentication,
+    // connect IDs of the connections initiated by `Host::connect`, per peer slot
+    initiated: Vec<u32>,
+    pending: Vec<Option<Pending>>,
+    // held packets of peers that authenticated, by peer slot, see `Authenticator::release`
+    released: VecDeque<(usize, u32, Held)>,
+}
+
+impl Authenticator {
+    /// Creates the authenticator of `host`.
+    pub(crate) fn new(host: *mut ENetHost, config: Authentication) -> Authenticator {
+        let peer_count = unsafe { (*host).peerCount };
+
+        Authenticator {
+            config,
+            initiated: vec![0; peer_count],
+            pending: (0..peer_count).map(|_| None).collect(),
+            released: VecDeque::new(),
+        }
+    }
+
+    /// Returns whether `peer` did not authenticate yet, so the application must not send anything to it.
+    pub(crate) unsafe fn is_pending(&self, peer: *mut ENetPeer) -> bool {
+        let index = (*peer).incomingPeerID as usize;
+        matches!(&self.pending[index], Some(pending) if pending.connect_id == (*peer).connectID)
+    }
+
+    /// Records that the connection `connect_id` in slot `index` was initiated by this host.
+    pub(crate) fn connecting(&mut self, index: usize, connect_id: u32) {
+        self.initiated[index] = connect_id;
+    }
+
+    /// Gives up on the pending peers that did not authenticate in time, see `give_up`.
+    pub(crate) unsafe fn expire(&mut self, host: *mut ENetHost, now: Instant) {
+        for (index, slot) in self.pending.iter_mut().enumerate() {
+            let pending = match slot {
+                Some(pending) if pending.deadline.is_some_and(|deadline| deadline <= now) => pending,
+                _ => continue,
+            };
+
+            let peer = (*host).peers.add(index);
+            if (*peer).connectID != pending.connect_id {
+                // the peer was reset by the application in the meantime
+                *slot = None;
+                continue;
+            }
+
+            debug!("peer {} did not authenticate in time", index);
+            give_up(slot, peer);
+        }
+    }
+
+    /// Handles `sys_event` and returns whether it is delivered to the application.
+    ///
+    /// Events of pending peers are withheld, except for the `Disconnect` of a connection this host initiated, and
+    /// their packets are held back. When a peer is authenticated, its event is turned into the `Connect` event the
+    /// application did not get yet, and the packets held back for the peer are released.
+    pub(crate) unsafe fn filter(&mut self, sys_event: &mut ENetEvent, connect_id: u32) -> bool {
+        if sys_event.peer.is_null() {
+            return true;
+        }
+
+        let index = (*sys_event.peer).incomingPeerID as usize;
+        let is_pending = matches!(&self.pending[index], Some(pending) if pending.connect_id == connect_id);
+
+        #[allow(non_upper_case_globals)]
+        match sys_event.type_ {
+            _ENetEventType_ENET_EVENT_TYPE_CONNECT => {
+                self.begin(sys_event.peer, index, connect_id);
+                false
+            }
+            _ENetEventType_ENET_EVENT_TYPE_DISCONNECT => {
+                self.released.retain(|(released_index, ..)| *released_index != index);
+                if !is_pending {
+                    return true;
+                }
+
+                let pending = self.pending[index].take().expect("pending peer");
+                matches!(pending.role, Role::Initiator)
+            }
+            _ENetEventType_ENET_EVENT_TYPE_RECEIVE if sys_event.channelID == self.config.channel_id => {
+                let packet = sys_event.packet;
+                let authenticated = is_pending && self.handle_message(sys_event.peer, index, packet_data(packet));
+                enet_packet_destroy(packet);
+
+                if authenticated {
+                    sys_event.type_ = _ENetEventType_ENET_EVENT_TYPE_CONNECT;
+                    sys_event.channelID = 0;
+                    sys_event.data = (*sys_event.peer).eventData;
+                    sys_event.packet = ptr::null_mut();
+                }
+                authenticated
+            }
+            _ENetEventType_ENET_EVENT_TYPE_RECEIVE if is_pending => {
+                // the accepting host may send packets right away, which can arrive before its acceptance
+                self.hold(index, sys_event);
+                enet_packet_destroy(sys_event.packet);
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Puts a newly connected peer into the pending state, and challenges it if this host accepted it.
+    unsafe fn begin(&mut self, peer: *mut ENetPeer, index: usize, connect_id: u32) {
+        let role = if self.initiated[index] == connect_id {
+            Role::Initiator
+        } else {
+            let mut challenge = [0; CHALLENGE_LEN];
+            if getrandom::getrandom(&mut challenge).is_err() {
+                debug!("could not generate a challenge for peer {}", index);
+                enet_peer_disconnect_now(peer, AUTHENTICATION_FAILED);
+                return;
+            }
+
+            let mut message = vec![MESSAGE_CHALLENGE];
+            message.extend_from_slice(&challenge);
+            if !self.send(peer, &message) {
+                enet_peer_disconnect_now(peer, AUTHENTICATION_FAILED);
+                return;
+            }
+
+            Role::Responder { challenge }
+        };
+
+        self.pending[index] = Some(Pending {
+            connect_id,
+            role,
+            deadline: Some(Instant::now() + self.config.timeout),
+            held: Vec::new(),
+        });
+    }
+
+    /// Handles a message of the pending peer in slot `index` and returns whether it is now authenticated.
+    unsafe fn handle_message(&mut self, peer: *mut ENetPeer, index: usize, data: &[u8]) -> bool {
+        let (connect_id, role) = match &self.pending[index] {
+            Some(pending) if pending.deadline.is_some() => (pending.connect_id, &pending.role),
+            // already given up on
+            _ => return false,
+        };
+        let challenge = match role {
+            Role::Initiator => None,
+            Role::Responder { challenge } => Some(*challenge),
+        };
+
+        match (challenge, data.split_first()) {
+            (None, Some((&MESSAGE_CHALLENGE, challenge))) if challenge.len() == CHALLENGE_LEN => {
+                let mut response = vec![MESSAGE_RESPONSE];
+                response.extend_from_slice(&self.config.mac(connect_id, challenge).finalize().into_bytes());
+
+                if !self.send(peer, &response) {
+                    self.fail(peer, index);
+                }
+                false
+            }
+            (None, Some((&MESSAGE_ACCEPT, []))) => {
+                self.authenticated(index);
+                true
+            }
+            (Some(challenge), Some((&MESSAGE_RESPONSE, response))) if response.len() == MAC_LEN => {
+                if self.config.mac(connect_id, &challenge).verify_slice(response).is_err()
+                    || !self.send(peer, &[MESSAGE_ACCEPT])
+                {
+                    self.fail(peer, index);
+                    return false;
+                }
+
+                self.authenticated(index);
+                true
+            }
+            _ => {
+                self.fail(peer, index);
+                false
+            }
+        }
+    }
+
+    /// Ends the pending state of the peer in slot `index`, releasing the packets held back for it.
+    fn authenticated(&mut self, index: usize) {
+        let pending = self.pending[index].take().expect("pending peer");
+        self.released
+            .extend(pending.held.into_iter().map(|held| (index, pending.connect_id, held)));
+    }
+
+    /// Holds back a copy of the packet received from the pending peer in slot `index` until it authenticated.
+    unsafe fn hold(&mut self, index: usize, sys_event: &ENetEvent) {
+        let pending = self.pending[index].as_mut().expect("pending peer");
+
+        if pending.deadline.is_none() {
+            // already given up on
+        } else if pending.held.len() < MAX_HELD_PACKETS {
+            let packet = sys_event.packet;
+            pending.held.push(Held {
+                channel_id: sys_event.channelID,
+                flags: (*packet).flags,
+                data: packet_data(packet).to_vec(),
+            });
+        } else {
+            debug!("peer {} sent too many packets before it authenticated", index);
+            self.fail(sys_event.peer, index);
+        }
+    }
+
+    /// Returns the next packet held back for a peer that authenticated since, as a `Receive` event with the connect
+    /// ID of the peer.
+    pub(crate) unsafe fn release(&mut self, host: *mut ENetHost) -> Option<(ENetEvent, u32)> {
+        while let Some((index, connect_id, held)) = self.released.pop_front() {
+            let peer = (*host).peers.add(index);
+            if (*peer).connectID != connect_id {
+                // the peer was reset by the application in the meantime
+                continue;
+            }
+
+            let flags = held.flags & !_ENetPacketFlag_ENET_PACKET_FLAG_NO_ALLOCATE;
+            let packet = enet_packet_create(held.data.as_ptr() as *const _, held.data.len(), flags);
+            if packet.is_null() {
+                continue;
+            }
+
+            let sys_event = ENetEvent {
+                type_: _ENetEventType_ENET_EVENT_TYPE_RECEIVE,
+                peer,
+                channelID: held.channel_id,
+                data: 0,
+                packet,
+            };
+            return Some((sys_event, connect_id));
+        }
+
+        None
+    }
+
+    unsafe fn fail(&mut self, peer: *mut ENetPeer, index: usize) {
+        debug!("peer {} failed to authenticate", index);
+        give_up(&mut self.pending[index], peer);
+    }
+
+    unsafe fn send(&self, peer: *mut ENetPeer, message: &[u8]) -> bool {
+        let packet = match Packet::new(message, PacketMode::ReliableSequenced) {
+            Ok(packet) => packet,
+            Err(_) => return false,
+        };
+
+        // fails if the peer was connected with too few channels to include the reserved one
+        let packet = packet.into_inner();
+        if enet_peer_send(peer, self.config.channel_id, packet) < 0 {
+            enet_packet_destroy(packet);
+            return false;
+        }
+        true
+    }
+}
+
+/// Gives up on the pending peer in `slot`.
+///
+/// Accepted peers are reset immediately, while peers this host connected to are disconnected,
+/// so the application gets the `Disconnect` event for the connection it initiated.
+unsafe fn give_up(slot: &mut Option<Pending>, peer: *mut ENetPeer) {
+    match slot {
+        Some(Pending {
+            role: Role::Initiator,
+            deadline,
+            held,
+            ..
+        }) => {
+            enet_peer_disconnect(peer, AUTHENTICATION_FAILED);
+            *deadline = None;
+            held.clear();
+        }
+        Some(Pending {
+            role: Role::Responder { .. },
+            ..
+        }) => {
+            enet_peer_disconnect_now(peer, AUTHENTICATION_FAILED);
+            *slot = None;
+        }
+        None => (),
+    }
+}
+
+unsafe fn packet_data<'a>(packet: *mut ENetPacket) -> &'a [u8] {
+    // ENet does not allocate any data for empty packets
+    if (*packet).data.is_null() {
+        return &[];
+    }
+
+    slice::from_raw_parts((*packet).data, (*packet).dataLength)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Authentication, AUTHENTICATION_FAILED};
+    use crate::{
+        tests::{localhost, service_until, ENET},
+        OwnedEvent, Packet, PacketMode, PeerError, PeerState,
+    };
+
+    #[test]
+    fn test_authentication() {
+        let address = localhost(12378);
+        let mut server = ENET.host_builder().address(address).build::<()>().unwrap();
+        server.set_authentication(Authentication::new(b"secret", 1));
+
+        let mut good_client = ENET.host_builder().build::<()>().unwrap();
+        good_client.set_authentication(Authentication::new(b"secret", 1));
+        let good_peer = good_client.connect(&address, 2, 0).unwrap();
+
+        let mut bad_client = ENET.host_builder().build::<()>().unwrap();
+        bad_client.set_authentication(Authentication::new(b"guess", 1));
+        let bad_peer = bad_client.connect(&address, 2, 0).unwrap();
+
+        let mut server_events = Vec::new();
+        let mut good_connected = false;
+        let mut bad_disconnected = false;
+
+        service_until(Duration::from_secs(2), || {
+            if let Some(event) = good_client.service(0).unwrap().map(OwnedEvent::from) {
+                assert!(matches!(event, OwnedEvent::Connect { peer, .. } if peer == good_peer));
+                let packet = Packet::new(b"hello", PacketMode::ReliableSequenced).unwrap();
+                good_client.peer_mut(good_peer).unwrap().send_packet(packet, 0).unwrap();
+                good_connected = true;
+            }
+
+            if let Some(event) = bad_client.service(0).unwrap().map(OwnedEvent::from) {
+                assert!(matches!(
+                    event,
+                    OwnedEvent::Disconnect { peer, data: AUTHENTICATION_FAILED, .. } if peer == bad_peer
+                ));
+                bad_disconnected = true;
+            }
+
+            if let Some(event) = server.service(5).unwrap() {
+                server_events.push(OwnedEvent::from(event));
+            }
+            Some(()).filter(|_| good_connected && bad_disconnected && server_events.len() == 2)
+        })
+        .expect("authentication timed out");
+
+        // only the authenticated peer was seen by the server application
+        let peer = server_events[0].peer_id();
+        assert!(matches!(server_events[0], OwnedEvent::Connect { .. }));
+        assert!(matches!(&server_events[1], OwnedEvent::Receive { peer: sender, channel_id: 0, .. } if *sender == peer));
+    }
+
+    #[test]
+    fn test_packets_follow_the_connect_event() {
+        let address = localhost(12391);
+        let mut server = ENET.host_builder().address(address).build::<()>().unwrap();
+        server.set_authentication(Authentication::new(b"secret", 1));
+
+        let mut client = ENET.host_builder().build::<()>().unwrap();
+        client.set_authentication(Authentication::new(b"secret", 1));
+        client.connect(&address, 2, 0).unwrap();
+        let mut client_events = Vec::new();
+
+        service_until(Duration::from_secs(2), || {
+            // the server greets the client as soon as it authenticated, which may be before the client did
+            if let Some(OwnedEvent::Connect { peer, .. }) = server.service(0).unwrap().map(OwnedEvent::from) {
+                let packet = Packet::new(b"welcome", PacketMode::ReliableSequenced).unwrap();
+                server.peer_mut(peer).unwrap().send_packet(packet, 0).unwrap();
+            }
+
+            if let Some(event) = client.service(5).unwrap() {
+                client_events.push(OwnedEvent::from(event));
+            }
+            Some(()).filter(|_| client_events.len() == 2)
+        })
+        .expect("greeting timed out");
+
+        assert!(matches!(client_events[0], OwnedEvent::Connect { .. }));
+        assert!(matches!(
+            &client_events[1],
+            OwnedEvent::Receive { channel_id: 0, packet, .. } if packet.data() == b"welcome"
+        ));
+    }
+
+    #[test]
+    fn test_pending_peer_times_out() {
+        let address = localhost(12383);
+        let mut server = ENET.host_builder().address(address).build::<()>().unwrap();
+        server.set_authentication(Authentication::new(b"secret", 1).timeout(Duration::from_millis(300)));
+
+        // never answers the challenge
+        let mut client = ENET.host_builder().build::<()>().unwrap();
+        let peer = client.connect(&address, 2, 0).unwrap();
+        let mut client_events = Vec::new();
+
+        let disconnect = service_until(Duration::from_secs(2), || {
+            // neither the connection nor the packets of the pending peer reach the application
+            if let Some(event) = server.service(0).unwrap() {
+                panic!("unexpected event: {:?}", OwnedEvent::from(event));
+            }
+
+            // and the application cannot send anything to it
+            server.broadcast(Packet::new(b"all", PacketMode::ReliableSequenced).unwrap(), 0);
+            for mut pending in server.peers().filter(|peer| peer.state() == PeerState::Connected) {
+                let packet = Packet::new(b"direct", PacketMode::ReliableSequenced).unwrap();
+                let res = pending.send_packet(packet, 0);
+                assert!(matches!(res, Err(PeerError::Unauthenticated(_))));
+            }
+
+            match client.service(5).unwrap().map(OwnedEvent::from) {
+                Some(OwnedEvent::Connect { .. }) => {
+                    let packet = Packet::new(b"hello", PacketMode::ReliableSequenced).unwrap();
+                    client.peer_mut(peer).unwrap().send_packet(packet, 0).unwrap();
+                    None
+                }
+                Some(OwnedEvent::Disconnect { data, .. }) => Some(data),
+                Some(event) => {
+                    client_events.push(event);
+                    None
+                }
+                None => None,
+            }
+        });
+
+        assert_eq!(disconnect, Some(AUTHENTICATION_FAILED));
+        // only the challenge on the reserved channel reached the client
+        assert!(matches!(&client_events[..], [OwnedEvent::Receive { channel_id: 1, .. }]));
+    }
+}
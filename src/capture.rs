@@ -0,0 +1,341 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::warn;
+
+use crate::Address;
+
+/// The pcap link type for raw IPv4/IPv6 packets without a link-layer header.
+const LINKTYPE_RAW: u32 = 101;
+
+const SNAPLEN: u32 = 65535;
+
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+const UDP_HEADER_LEN: usize = 8;
+const IPPROTO_UDP: u8 = 17;
+
+/// A running capture of the datagrams of a `Host`, see `Host::start_capture`.
+pub(crate) struct Capture {
+    writer: Box<dyn Write + Send>,
+    local_address: Address,
+    // the first write error, after which nothing is written anymore
+    error: Option<io::Error>,
+}
+
+/// A `Capture`, shared by a `Host` with its `Socket`s and `InterceptContext`s.
+pub(crate) type SharedCapture = Arc<Mutex<Capture>>;
+
+impl std::fmt::Debug for Capture {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Capture")
+            .field("local_address", &self.local_address)
+            .field("error", &self.error)
+            .finish()
+    }
+}
+
+impl Capture {
+    /// Creates a pcap file at `path` for a host bound to `local_address`.
+    pub(crate) fn create(path: &Path, local_address: Address) -> io::Result<Capture> {
+        Capture::new(Box::new(BufWriter::new(File::create(path)?)), local_address)
+    }
+
+    fn new(mut writer: Box<dyn Write + Send>, local_address: Address) -> io::Result<Capture> {
+        writer.write_all(&0xa1b2_c3d4u32.to_le_bytes())?;
+        writer.write_all(&2u16.to_le_bytes())?;
+        writer.write_all(&4u16.to_le_bytes())?;
+        // time zone offset and timestamp accuracy, always 0
+        writer.write_all(&[0; 8])?;
+        writer.write_all(&SNAPLEN.to_le_bytes())?;
+        writer.write_all(&LINKTYPE_RAW.to_le_bytes())?;
+
+        Ok(Capture {
+            writer,
+            local_address,
+            error: None,
+        })
+    }
+
+    pub(crate) fn lock(shared: &SharedCapture) -> MutexGuard<'_, Capture> {
+        shared.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Records a datagram received from `from`.
+    pub(crate) fn record_received(&mut self, from: Address, data: &[u8]) {
+        self.record(from.0, self.local_address.0, &[data]);
+    }
+
+    /// Records a datagram consisting of the concatenation of `buffers`, sent to `to`.
+    pub(crate) fn record_sent(&mut self, to: Address, buffers: &[&[u8]]) {
+        self.record(self.local_address.0, to.0, buffers);
+    }
+
+    /// Flushes the capture, returning the error that stopped it early, if any.
+    pub(crate) fn finish(&mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(err) => Err(err),
+            None => self.writer.flush(),
+        }
+    }
+
+    fn record(&mut self, source: SocketAddr, destination: SocketAddr, payload: &[&[u8]]) {
+        if self.error.is_some() {
+            return;
+        }
+
+        let packet = ip_packet(source, destination, payload);
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let captured_len = packet.len().min(SNAPLEN as usize);
+
+        let mut record = Vec::with_capacity(16 + captured_len);
+        record.extend_from_slice(&(timestamp.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&timestamp.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(captured_len as u32).to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(&packet[..captured_len]);
+
+        if let Err(err) = self.writer.write_all(&record) {
+            warn!("stopping capture after failing to write to it: {}", err);
+            self.error = Some(err);
+        }
+    }
+}
+
+/// Builds an IP packet carrying a UDP datagram with `payload` from `source` to `destination`.
+///
+/// Both addresses are IPv4 where possible, as ENet reports IPv4 addresses as such. An unspecified
+/// local address (of a host bound to all interfaces) is replaced by the unspecified address of the other one's family.
+fn ip_packet(source: SocketAddr, destination: SocketAddr, payload: &[&[u8]]) -> Vec<u8> {
+    let (source_ip, destination_ip) = match (unmap(source.ip()), unmap(destination.ip())) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => (IpAddr::V4(s), IpAddr::V4(d)),
+        (s, d) if s.is_unspecified() && d.is_ipv4() => (IpAddr::V4(Ipv4Addr::UNSPECIFIED), d),
+        (s, d) if d.is_unspecified() && s.is_ipv4() => (s, IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+        (s, d) => (IpAddr::V6(to_ipv6(s)), IpAddr::V6(to_ipv6(d))),
+    };
+
+    let payload_len: usize = payload.iter().map(|buffer| buffer.len()).sum();
+    // the length fields only have 16 bits, which ENet's datagrams never exceed
+    let udp_len = (UDP_HEADER_LEN + payload_len).min(u16::MAX as usize) as u16;
+
+    let mut udp = Vec::with_capacity(UDP_HEADER_LEN + payload_len);
+    udp.extend_from_slice(&source.port().to_be_bytes());
+    udp.extend_from_slice(&destination.port().to_be_bytes());
+    udp.extend_from_slice(&udp_len.to_be_bytes());
+    udp.extend_from_slice(&[0, 0]);
+    for buffer in payload {
+        udp.extend_from_slice(buffer);
+    }
+
+    let mut packet = match (source_ip, destination_ip) {
+        (IpAddr::V4(source_ip), IpAddr::V4(destination_ip)) => {
+            let mut header = Vec::with_capacity(IPV4_HEADER_LEN + udp.len());
+            // version 4, header length of 5 words
+            header.push(0x45);
+            header.push(0);
+            header.extend_from_slice(&(IPV4_HEADER_LEN as u16).saturating_add(udp_len).to_be_bytes());
+            // identification, flags and fragment offset
+            header.extend_from_slice(&[0, 0, 0, 0]);
+            header.push(64);
+            header.push(IPPROTO_UDP);
+            header.extend_from_slice(&[0, 0]);
+            header.extend_from_slice(&source_ip.octets());
+            header.extend_from_slice(&destination_ip.octets());
+
+            let header_checksum = internet_checksum(&[&header]);
+            header[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+
+            let pseudo_header = [
+                &source_ip.octets()[..],
+                &destination_ip.octets(),
+                &[0, IPPROTO_UDP],
+                &udp_len.to_be_bytes(),
+            ]
+            .concat();
+            set_udp_checksum(&mut udp, &pseudo_header);
+
+            header
+        }
+        (IpAddr::V6(source_ip), IpAddr::V6(destination_ip)) => {
+            let mut header = Vec::with_capacity(IPV6_HEADER_LEN + udp.len());
+            // version 6, no traffic class or flow label
+            header.extend_from_slice(&[0x60, 0, 0, 0]);
+            header.extend_from_slice(&udp_len.to_be_bytes());
+            header.push(IPPROTO_UDP);
+            header.push(64);
+            header.extend_from_slice(&source_ip.octets());
+            header.extend_from_slice(&destination_ip.octets());
+
+            let pseudo_header = [
+                &source_ip.octets()[..],
+                &destination_ip.octets(),
+                &u32::from(udp_len).to_be_bytes(),
+                &[0, 0, 0, IPPROTO_UDP],
+            ]
+            .concat();
+            set_udp_checksum(&mut udp, &pseudo_header);
+
+            header
+        }
+        _ => unreachable!("addresses are of the same family"),
+    };
+
+    packet.extend_from_slice(&udp);
+    packet
+}
+
+fn unmap(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        v4 => v4,
+    }
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(v4) if v4.is_unspecified() => Ipv6Addr::UNSPECIFIED,
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
+    }
+}
+
+fn set_udp_checksum(udp: &mut [u8], pseudo_header: &[u8]) {
+    let checksum = match internet_checksum(&[pseudo_header, udp]) {
+        // a checksum of 0 means "no checksum" for UDP
+        0 => 0xFFFF,
+        checksum => checksum,
+    };
+
+    udp[6..8].copy_from_slice(&checksum.to_be_bytes());
+}
+
+/// The 16 bit one's complement checksum used by IP and UDP (RFC 1071) over the concatenation of `data`,
+/// whose parts all have an even length except for the last one.
+fn internet_checksum(data: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+
+    for part in data {
+        for chunk in part.chunks(2) {
+            let word = u16::from_be_bytes([chunk[0], chunk.get(1).copied().unwrap_or(0)]);
+            sum += u32::from(word);
+        }
+    }
+
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+        time::Duration,
+    };
+
+    use super::{internet_checksum, ip_packet};
+    use crate::{
+        tests::{connect_hosts, localhost, ENET},
+        Address,
+    };
+
+    #[test]
+    fn test_ip_packet() {
+        let source = "127.0.0.1:1234".parse().unwrap();
+        let destination = "[::ffff:127.0.0.2]:5678".parse().unwrap();
+        let packet = ip_packet(source, destination, &[b"he", b"llo"]);
+
+        assert_eq!(packet.len(), 20 + 8 + 5);
+        assert_eq!(packet[0], 0x45);
+        assert_eq!(&packet[16..20], &[127, 0, 0, 2]);
+        assert_eq!(internet_checksum(&[&packet[..20]]), 0);
+        assert_eq!(&packet[20..24], &[0x04, 0xD2, 0x16, 0x2E]);
+        assert_eq!(&packet[28..], b"hello");
+
+        let packet = ip_packet("[::]:1234".parse().unwrap(), "[::1]:5678".parse().unwrap(), &[b"hello"]);
+        assert_eq!(packet.len(), 40 + 8 + 5);
+        assert_eq!(packet[0] >> 4, 6);
+        assert_eq!(&packet[4..6], &[0, 13]);
+    }
+
+    #[test]
+    fn test_capture_file() {
+        let address = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 12377));
+        let mut host = ENET.host_builder().address(Address(address)).build::<()>().unwrap();
+
+        let path = std::env::temp_dir().join(format!("enet-capture-{}.pcap", std::process::id()));
+        host.start_capture(&path).unwrap();
+        host.register_oob_handler("ping", |context, payload| {
+            context.reply_oob("pong", payload).unwrap();
+        });
+
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        socket.send_to(b"\xFF\xFF\xFF\xFFping 1", address).unwrap();
+        assert!(host.service(100).unwrap().is_none());
+        socket.recv_from(&mut [0; 32]).unwrap();
+
+        host.stop_capture().unwrap();
+        let capture = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(&capture[..4], &0xa1b2_c3d4u32.to_le_bytes());
+        assert_eq!(&capture[20..24], &101u32.to_le_bytes());
+
+        // the request, then the reply, each with a record header, an IPv4 header and a UDP header
+        let request_len = 16 + 20 + 8 + 10;
+        let reply_len = 16 + 20 + 8 + 10;
+        assert_eq!(capture.len(), 24 + request_len + reply_len);
+        assert_eq!(&capture[24 + 16 + 28..24 + request_len], b"\xFF\xFF\xFF\xFFping 1");
+        assert_eq!(&capture[24 + request_len + 16 + 28..], b"\xFF\xFF\xFF\xFFpong\n1");
+    }
+
+    #[test]
+    fn test_capture_connection() {
+        let address = localhost(12390);
+        let mut server = ENET.host_builder().address(address).build::<()>().unwrap();
+        let mut client = ENET.host_builder().address(localhost(0)).build::<()>().unwrap();
+        let client_address = client.local_addr().unwrap();
+
+        let path = std::env::temp_dir().join(format!("enet-capture-connection-{}.pcap", std::process::id()));
+        server.start_capture(&path).unwrap();
+        let (peer, _) = connect_hosts(&mut server, &mut client, &address);
+        server.send_oob(&client_address, "hello", b"").unwrap();
+
+        // the traffic is recorded where it passes the socket, without being rerouted
+        assert_eq!(server.peer(peer).unwrap().address(), client_address);
+
+        server.stop_capture().unwrap();
+        let capture = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        // the source and destination port and the payload of each record, behind its headers
+        let mut records = Vec::new();
+        let mut rest = &capture[24..];
+        while !rest.is_empty() {
+            let len = u32::from_le_bytes(rest[8..12].try_into().unwrap()) as usize;
+            let udp = &rest[16 + 20..16 + len];
+            let ports = (u16::from_be_bytes([udp[0], udp[1]]), u16::from_be_bytes([udp[2], udp[3]]));
+            records.push((ports, udp[8..].to_vec()));
+            rest = &rest[16 + len..];
+        }
+
+        // ENet's own datagrams are only recorded as received, the OOB message as sent
+        let received = (client_address.port(), address.port());
+        let sent = (address.port(), client_address.port());
+        assert!(records.iter().any(|(ports, _)| *ports == received));
+        assert_eq!(
+            records.iter().filter(|(ports, _)| *ports == sent).collect::<Vec<_>>(),
+            [&(sent, b"\xFF\xFF\xFF\xFFhello".to_vec())]
+        );
+    }
+}
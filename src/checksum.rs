@@ -0,0 +1,148 @@
+use std::{
+    any::{type_name, TypeId},
+    fmt,
+    hash::{Hash, Hasher},
+    panic,
+};
+
+use log::error;
+
+use citizen_enet_sys::{enet_crc32, ENetBuffer, ENetChecksumCallback};
+
+use crate::compress::buffer_slice;
+
+/// A checksum algorithm for datagrams, which can be plugged into a `Host` through `Checksum::custom`.
+///
+/// ENet's checksum callback does not carry any context, so algorithms are selected by type and cannot have state.
+pub trait ChecksumAlgorithm: 'static {
+    /// Computes the checksum over the concatenation of `buffers`.
+    fn checksum(buffers: &[&[u8]]) -> u32;
+}
+
+/// A checksum that ENet attaches to, and verifies on, every datagram sent or received by a `Host`.
+///
+/// Both ends of a connection have to be configured with the same checksum,
+/// as ENet silently drops datagrams that fail verification.
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub enum Checksum {
+    /// Datagrams are not checksummed (ENet default).
+    None,
+    /// Datagrams are checksummed using ENet's built-in CRC32 implementation.
+    Crc32,
+    /// Datagrams are checksummed using a custom `ChecksumAlgorithm`, see `Checksum::custom`.
+    Custom(CustomChecksum),
+}
+
+impl Checksum {
+    /// Returns a `Checksum` using the `ChecksumAlgorithm` `A`.
+    pub fn custom<A: ChecksumAlgorithm>() -> Checksum {
+        Checksum::Custom(CustomChecksum {
+            type_id: TypeId::of::<A>(),
+            type_name: type_name::<A>(),
+            callback: checksum::<A>,
+        })
+    }
+
+    pub(crate) fn to_sys_callback(self) -> ENetChecksumCallback {
+        match self {
+            Checksum::None => None,
+            Checksum::Crc32 => Some(enet_crc32),
+            Checksum::Custom(custom) => Some(custom.callback),
+        }
+    }
+}
+
+/// A custom checksum algorithm, created through `Checksum::custom`.
+#[derive(Copy, Clone)]
+pub struct CustomChecksum {
+    type_id: TypeId,
+    type_name: &'static str,
+    callback: unsafe extern "C" fn(*const ENetBuffer, usize) -> u32,
+}
+
+impl fmt::Debug for CustomChecksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.type_name)
+    }
+}
+
+impl PartialEq for CustomChecksum {
+    fn eq(&self, other: &CustomChecksum) -> bool {
+        self.type_id == other.type_id
+    }
+}
+
+impl Eq for CustomChecksum {}
+
+impl Hash for CustomChecksum {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.type_id.hash(state);
+    }
+}
+
+unsafe extern "C" fn checksum<A: ChecksumAlgorithm>(buffers: *const ENetBuffer, buffer_count: usize) -> u32 {
+    let buffers: Vec<&[u8]> = std::slice::from_raw_parts(buffers, buffer_count)
+        .iter()
+        .map(|buffer| buffer_slice(buffer))
+        .collect();
+
+    // a wrong checksum only makes ENet drop the datagram, so that is how a panic is handled
+    panic::catch_unwind(|| A::checksum(&buffers)).unwrap_or_else(|err| {
+        error!("panic in ChecksumAlgorithm::checksum: {:?}", err);
+        0
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Checksum, ChecksumAlgorithm};
+    use crate::{
+        tests::{localhost, service_until, ENET},
+        Event,
+    };
+
+    /// Sums up all bytes, which is deliberately incompatible with CRC32.
+    struct ByteSum;
+
+    impl ChecksumAlgorithm for ByteSum {
+        fn checksum(buffers: &[&[u8]]) -> u32 {
+            buffers.iter().flat_map(|b| b.iter()).map(|&b| b as u32).sum()
+        }
+    }
+
+    fn connects(server_checksum: Checksum, client_checksum: Checksum, port: u16) -> bool {
+        let address = localhost(port);
+
+        let mut server = ENET
+            .host_builder()
+            .address(address)
+            .checksum(server_checksum)
+            .build::<()>()
+            .unwrap();
+        let mut client = ENET.host_builder().checksum(client_checksum).build::<()>().unwrap();
+
+        client.connect(&address, 1, 0).unwrap();
+
+        service_until(Duration::from_secs(1), || {
+            server.service(0).unwrap();
+            match client.service(5).unwrap() {
+                Some(Event::Connect(_)) => Some(()),
+                _ => None,
+            }
+        })
+        .is_some()
+    }
+
+    #[test]
+    fn test_matching_checksums_connect() {
+        assert!(connects(Checksum::Crc32, Checksum::Crc32, 12350));
+        assert!(connects(Checksum::custom::<ByteSum>(), Checksum::custom::<ByteSum>(), 12351));
+    }
+
+    #[test]
+    fn test_mismatched_checksums_drop_datagrams() {
+        assert!(!connects(Checksum::Crc32, Checksum::custom::<ByteSum>(), 12352));
+    }
+}
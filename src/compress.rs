@@ -0,0 +1,352 @@
+use std::{
+    ffi::c_void,
+    fmt, panic, slice,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use log::error;
+
+use citizen_enet_sys::{
+    enet_range_coder_compress, enet_range_coder_create, enet_range_coder_decompress, enet_range_coder_destroy,
+    ENetBuffer, ENetCompressor,
+};
+
+use crate::CompressionError;
+
+/// A compression algorithm for datagrams, which can be plugged into a `Host` through `Compression::custom`.
+///
+/// ENet compresses the payload of each datagram (everything after the protocol header) separately.
+/// The receiving end has to use a compatible `Compressor` to be able to decompress the datagrams.
+pub trait Compressor: Send + 'static {
+    /// Compresses the concatenation of `input` into `output`, returning the compressed length.
+    ///
+    /// Returning `None`, or a length that is not smaller than the input, makes ENet send the datagram uncompressed.
+    fn compress(&mut self, input: &[&[u8]], output: &mut [u8]) -> Option<usize>;
+
+    /// Decompresses `input` into `output`, returning the decompressed length.
+    ///
+    /// Returning `None` makes ENet drop the datagram.
+    fn decompress(&mut self, input: &[u8], output: &mut [u8]) -> Option<usize>;
+}
+
+/// Compression that ENet applies to the datagrams sent by a `Host`.
+///
+/// ENet only sends a datagram compressed if that actually makes it smaller,
+/// and the receiving end has to be configured with the same compression to be able to decompress it.
+pub enum Compression {
+    /// Datagrams are not compressed (ENet default).
+    None,
+    /// Datagrams are compressed using ENet's built-in adaptive range coder.
+    RangeCoder,
+    /// Datagrams are compressed using a custom `Compressor`.
+    Custom(Box<dyn Compressor>),
+}
+
+impl Compression {
+    /// Returns a `Compression` using the given `Compressor`.
+    pub fn custom<C: Compressor>(compressor: C) -> Compression {
+        Compression::Custom(Box::new(compressor))
+    }
+}
+
+impl fmt::Debug for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::None => f.write_str("None"),
+            Compression::RangeCoder => f.write_str("RangeCoder"),
+            Compression::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+/// A snapshot of the compression counters of a `Host`, as returned by `Host::compression_stats`.
+///
+/// The counters only cover datagrams handled while a `Compression` other than `Compression::None` was set.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct CompressionStats {
+    /// Number of outgoing datagrams that were sent compressed.
+    pub datagrams_compressed: u64,
+    /// Number of outgoing datagrams that were sent uncompressed, because compression would not have made them smaller.
+    pub datagrams_uncompressed: u64,
+    /// Total payload size of all outgoing datagrams before compression, in bytes.
+    pub bytes_in: u64,
+    /// Total payload size of all outgoing datagrams as actually sent, in bytes.
+    pub bytes_out: u64,
+    /// Number of incoming datagrams that were successfully decompressed.
+    pub datagrams_decompressed: u64,
+    /// Number of incoming datagrams that were dropped because they could not be decompressed.
+    pub decompression_failures: u64,
+}
+
+impl CompressionStats {
+    /// Returns the ratio between the sent and the original payload size of outgoing datagrams.
+    ///
+    /// Values below 1.0 mean bandwidth was saved; 1.0 is returned if nothing was sent yet.
+    pub fn ratio(&self) -> f64 {
+        if self.bytes_in == 0 {
+            return 1.0;
+        }
+
+        self.bytes_out as f64 / self.bytes_in as f64
+    }
+
+    /// Returns the number of payload bytes that compression saved on outgoing datagrams.
+    pub fn bytes_saved(&self) -> u64 {
+        self.bytes_in.saturating_sub(self.bytes_out)
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct CompressionCounters {
+    datagrams_compressed: AtomicU64,
+    datagrams_uncompressed: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    datagrams_decompressed: AtomicU64,
+    decompression_failures: AtomicU64,
+}
+
+impl CompressionCounters {
+    pub(crate) fn snapshot(&self) -> CompressionStats {
+        CompressionStats {
+            datagrams_compressed: self.datagrams_compressed.load(Ordering::Relaxed),
+            datagrams_uncompressed: self.datagrams_uncompressed.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            datagrams_decompressed: self.datagrams_decompressed.load(Ordering::Relaxed),
+            decompression_failures: self.decompression_failures.load(Ordering::Relaxed),
+        }
+    }
+
+    fn record_compress(&self, in_limit: usize, compressed: usize) {
+        // mirrors ENet, which only uses the compressed data if it is actually smaller
+        let sent = if compressed > 0 && compressed < in_limit {
+            self.datagrams_compressed.fetch_add(1, Ordering::Relaxed);
+            compressed
+        } else {
+            self.datagrams_uncompressed.fetch_add(1, Ordering::Relaxed);
+            in_limit
+        };
+
+        self.bytes_in.fetch_add(in_limit as u64, Ordering::Relaxed);
+        self.bytes_out.fetch_add(sent as u64, Ordering::Relaxed);
+    }
+
+    fn record_decompress(&self, decompressed: usize) {
+        if decompressed > 0 {
+            self.datagrams_decompressed.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.decompression_failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+struct RangeCoderContext(*mut c_void);
+
+impl Drop for RangeCoderContext {
+    fn drop(&mut self) {
+        unsafe {
+            enet_range_coder_destroy(self.0);
+        }
+    }
+}
+
+enum CompressorKind {
+    RangeCoder(RangeCoderContext),
+    Custom(Box<dyn Compressor>),
+}
+
+/// The context handed to ENet. It is owned by the ENet host, which frees it through `destroy_context`.
+struct CompressorContext {
+    kind: CompressorKind,
+    counters: Arc<CompressionCounters>,
+}
+
+/// Builds the `ENetCompressor` for `compression`, or returns `None` for `Compression::None`.
+pub(crate) fn to_sys_compressor(
+    compression: Compression,
+    counters: Arc<CompressionCounters>,
+) -> Result<Option<ENetCompressor>, CompressionError> {
+    let kind = match compression {
+        Compression::None => return Ok(None),
+        Compression::RangeCoder => {
+            let context = unsafe { enet_range_coder_create() };
+
+            if context.is_null() {
+                return Err(CompressionError::RangeCoderCreationFailed);
+            }
+
+            CompressorKind::RangeCoder(RangeCoderContext(context))
+        }
+        Compression::Custom(compressor) => CompressorKind::Custom(compressor),
+    };
+
+    let context = Box::new(CompressorContext { kind, counters });
+
+    Ok(Some(ENetCompressor {
+        context: Box::into_raw(context) as *mut c_void,
+        compress: Some(compress),
+        decompress: Some(decompress),
+        destroy: Some(destroy_context),
+    }))
+}
+
+pub(crate) unsafe fn buffer_slice<'a>(buffer: &ENetBuffer) -> &'a [u8] {
+    if buffer.dataLength == 0 {
+        return &[];
+    }
+
+    slice::from_raw_parts(buffer.data as *const u8, buffer.dataLength)
+}
+
+unsafe extern "C" fn compress(
+    context: *mut c_void,
+    in_buffers: *const ENetBuffer,
+    in_buffer_count: usize,
+    in_limit: usize,
+    out_data: *mut u8,
+    out_limit: usize,
+) -> usize {
+    let context = &mut *(context as *mut CompressorContext);
+
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| match &mut context.kind {
+        CompressorKind::RangeCoder(range_coder) => {
+            enet_range_coder_compress(range_coder.0, in_buffers, in_buffer_count, in_limit, out_data, out_limit)
+        }
+        CompressorKind::Custom(compressor) => {
+            let input: Vec<&[u8]> = slice::from_raw_parts(in_buffers, in_buffer_count)
+                .iter()
+                .map(|buffer| buffer_slice(buffer))
+                .collect();
+            let output = slice::from_raw_parts_mut(out_data, out_limit);
+
+            match compressor.compress(&input, output) {
+                Some(len) if len <= out_limit => len,
+                _ => 0,
+            }
+        }
+    }));
+
+    let compressed = result.unwrap_or_else(|err| {
+        error!("panic in Compressor::compress: {:?}", err);
+        0
+    });
+
+    context.counters.record_compress(in_limit, compressed);
+    compressed
+}
+
+unsafe extern "C" fn decompress(
+    context: *mut c_void,
+    in_data: *const u8,
+    in_limit: usize,
+    out_data: *mut u8,
+    out_limit: usize,
+) -> usize {
+    let context = &mut *(context as *mut CompressorContext);
+
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| match &mut context.kind {
+        CompressorKind::RangeCoder(range_coder) => {
+            enet_range_coder_decompress(range_coder.0, in_data, in_limit, out_data, out_limit)
+        }
+        CompressorKind::Custom(compressor) => {
+            let input = slice::from_raw_parts(in_data, in_limit);
+            let output = slice::from_raw_parts_mut(out_data, out_limit);
+
+            match compressor.decompress(input, output) {
+                Some(len) if len <= out_limit => len,
+                _ => 0,
+            }
+        }
+    }));
+
+    let decompressed = result.unwrap_or_else(|err| {
+        error!("panic in Compressor::decompress: {:?}", err);
+        0
+    });
+
+    context.counters.record_decompress(decompressed);
+    decompressed
+}
+
+unsafe extern "C" fn destroy_context(context: *mut c_void) {
+    drop(Box::from_raw(context as *mut CompressorContext));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::c_void, sync::Arc};
+
+    use citizen_enet_sys::ENetBuffer;
+
+    use super::{to_sys_compressor, CompressionCounters, Compression, Compressor};
+
+    /// Drops every second byte on compression, and duplicates every byte on decompression.
+    struct HalvingCompressor;
+
+    impl Compressor for HalvingCompressor {
+        fn compress(&mut self, input: &[&[u8]], output: &mut [u8]) -> Option<usize> {
+            let bytes: Vec<u8> = input.iter().flat_map(|b| b.iter()).step_by(2).copied().collect();
+            output[..bytes.len()].copy_from_slice(&bytes);
+            Some(bytes.len())
+        }
+
+        fn decompress(&mut self, input: &[u8], output: &mut [u8]) -> Option<usize> {
+            if input.len() * 2 > output.len() {
+                return None;
+            }
+
+            for (i, b) in input.iter().enumerate() {
+                output[2 * i] = *b;
+                output[2 * i + 1] = *b;
+            }
+
+            Some(input.len() * 2)
+        }
+    }
+
+    #[test]
+    fn test_custom_compressor_stats() {
+        let counters = Arc::new(CompressionCounters::default());
+        let sys = to_sys_compressor(Compression::custom(HalvingCompressor), counters.clone())
+            .unwrap()
+            .unwrap();
+
+        let mut first = *b"aabb";
+        let mut second = *b"cc";
+        let buffers = [
+            ENetBuffer { data: first.as_mut_ptr() as *mut c_void, dataLength: first.len() },
+            ENetBuffer { data: second.as_mut_ptr() as *mut c_void, dataLength: second.len() },
+        ];
+        let mut compressed = [0u8; 6];
+        let len = unsafe {
+            (sys.compress.unwrap())(sys.context, buffers.as_ptr(), 2, 6, compressed.as_mut_ptr(), 6)
+        };
+        assert_eq!(&compressed[..len], b"abc");
+
+        let mut decompressed = [0u8; 6];
+        let len = unsafe {
+            (sys.decompress.unwrap())(sys.context, compressed.as_ptr(), 3, decompressed.as_mut_ptr(), 6)
+        };
+        assert_eq!(&decompressed[..len], b"aabbcc");
+
+        let len = unsafe {
+            (sys.decompress.unwrap())(sys.context, compressed.as_ptr(), 3, decompressed.as_mut_ptr(), 4)
+        };
+        assert_eq!(len, 0);
+
+        let stats = counters.snapshot();
+        assert_eq!(stats.datagrams_compressed, 1);
+        assert_eq!(stats.bytes_in, 6);
+        assert_eq!(stats.bytes_out, 3);
+        assert_eq!(stats.bytes_saved(), 3);
+        assert_eq!(stats.ratio(), 0.5);
+        assert_eq!(stats.datagrams_decompressed, 1);
+        assert_eq!(stats.decompression_failures, 1);
+
+        unsafe { (sys.destroy.unwrap())(sys.context) };
+    }
+}
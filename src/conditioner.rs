@@ -0,0 +1,500 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{hash_map::RandomState, BinaryHeap, VecDeque},
+    hash::{BuildHasher, Hasher},
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use log::debug;
+
+use citizen_enet_sys::ENetSocket;
+
+use crate::{socket, Address, BandwidthLimit, Error};
+
+/// Datagrams that would wait longer than this for the simulated link are dropped, like by a router with a full queue.
+const MAX_QUEUE_DELAY: Duration = Duration::from_secs(1);
+
+/// The minimum extra delay of a reordered datagram, so that it is overtaken even without latency and jitter.
+const MIN_REORDER_DELAY: Duration = Duration::from_millis(10);
+
+/// Simulates a bad network for a `Host`, see `Host::set_network_conditioner`.
+///
+/// Every datagram received by the host, and every datagram sent through `Host::socket`, `Host::send_oob` or an
+/// `InterceptContext`, goes through the conditioner, which may drop, delay, duplicate or reorder it.
+/// Datagrams sent by ENet itself are written to the socket directly and cannot be conditioned when they are sent;
+/// to condition both directions of an ENet connection, attach a conditioner to both hosts.
+///
+/// All random decisions are drawn from a generator seeded with `NetworkConditioner::seed`, so a failure
+/// can be reproduced by creating the conditioner through `NetworkConditioner::with_seed` with the same seed
+/// (as long as the datagrams arrive in the same order).
+#[derive(Debug, Clone)]
+pub struct NetworkConditioner {
+    seed: u64,
+    rng: SplitMix64,
+    packet_loss: f64,
+    latency: Duration,
+    jitter: Duration,
+    duplication: f64,
+    reordering: f64,
+    bandwidth_limit: BandwidthLimit,
+    // when the simulated links are idle again, per direction, see `NetworkConditioner::transmit`
+    link_idle_at: [Option<Instant>; 2],
+}
+
+impl Default for NetworkConditioner {
+    fn default() -> Self {
+        NetworkConditioner::new()
+    }
+}
+
+impl NetworkConditioner {
+    /// Creates a conditioner that does not change any datagrams yet, with a random seed.
+    pub fn new() -> Self {
+        NetworkConditioner::with_seed(RandomState::new().build_hasher().finish())
+    }
+
+    /// Creates a conditioner that does not change any datagrams yet, with the given seed.
+    pub fn with_seed(seed: u64) -> Self {
+        NetworkConditioner {
+            seed,
+            rng: SplitMix64(seed),
+            packet_loss: 0.0,
+            latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+            duplication: 0.0,
+            reordering: 0.0,
+            bandwidth_limit: BandwidthLimit::Unlimited,
+            link_idle_at: [None; 2],
+        }
+    }
+
+    /// Returns the seed of this conditioner, e.g. to log it for reproducing a failed test.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Sets the probability that a datagram is lost, between 0.0 and 1.0.
+    pub fn packet_loss(mut self, probability: f64) -> Self {
+        self.packet_loss = probability.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Sets the fixed delay added to every datagram.
+    pub fn latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Sets the maximum random delay added to every datagram on top of the latency, distributed uniformly.
+    ///
+    /// Like on a real network, jitter larger than the interval between datagrams also reorders them.
+    pub fn jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Sets the probability that a datagram is delivered twice, between 0.0 and 1.0.
+    /// Both copies are delayed independently.
+    pub fn duplication(mut self, probability: f64) -> Self {
+        self.duplication = probability.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Sets the probability that a datagram is held back, so that datagrams sent after it overtake it,
+    /// between 0.0 and 1.0.
+    ///
+    /// A held back datagram is delayed by an extra `latency + jitter`, but at least 10ms.
+    pub fn reordering(mut self, probability: f64) -> Self {
+        self.reordering = probability.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Limits the bandwidth of the simulated network in each direction, in bytes/second.
+    ///
+    /// Datagrams are queued until the link is free, and dropped if they would have to wait for more than a second.
+    pub fn bandwidth_limit(mut self, bandwidth_limit: BandwidthLimit) -> Self {
+        self.bandwidth_limit = bandwidth_limit;
+        self
+    }
+
+    /// Decides the fate of a datagram of `len` bytes that enters the simulated network at `now`,
+    /// returning when each of its copies leaves the network. No copies means it was lost.
+    fn schedule(&mut self, direction: Direction, len: usize, now: Instant) -> Vec<Instant> {
+        if self.rng.chance(self.packet_loss) {
+            return Vec::new();
+        }
+
+        let copies = if self.rng.chance(self.duplication) { 2 } else { 1 };
+        let mut departures = Vec::with_capacity(copies);
+
+        for _ in 0..copies {
+            let sent_at = match self.transmit(direction, len, now) {
+                Some(sent_at) => sent_at,
+                None => continue,
+            };
+
+            let mut delay = self.latency + self.jitter.mul_f64(self.rng.next_f64());
+            if self.rng.chance(self.reordering) {
+                delay += (self.latency + self.jitter).max(MIN_REORDER_DELAY);
+            }
+
+            departures.push(sent_at + delay);
+        }
+
+        departures
+    }
+
+    /// Puts a datagram of `len` bytes on the link for `direction`, returning when it has been transmitted,
+    /// or `None` if the link is too busy and the datagram is dropped.
+    fn transmit(&mut self, direction: Direction, len: usize, now: Instant) -> Option<Instant> {
+        let bytes_per_second = match self.bandwidth_limit {
+            BandwidthLimit::Unlimited | BandwidthLimit::Limited(0) => return Some(now),
+            BandwidthLimit::Limited(bytes_per_second) => bytes_per_second,
+        };
+
+        let idle_at = &mut self.link_idle_at[direction as usize];
+        let start = idle_at.map_or(now, |idle_at| idle_at.max(now));
+
+        if start - now > MAX_QUEUE_DELAY {
+            return None;
+        }
+
+        let sent_at = start + Duration::from_secs_f64(len as f64 / f64::from(bytes_per_second));
+        *idle_at = Some(sent_at);
+
+        Some(sent_at)
+    }
+}
+
+/// The direction of a datagram through the simulated network.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Direction {
+    Incoming = 0,
+    Outgoing = 1,
+}
+
+/// A datagram held back by the conditioner until it is due.
+#[derive(Debug)]
+struct Delayed {
+    due: Instant,
+    // keeps datagrams that are due at the same time in order
+    sequence: u64,
+    direction: Direction,
+    address: Address,
+    data: Vec<u8>,
+}
+
+impl PartialEq for Delayed {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Delayed {}
+
+impl PartialOrd for Delayed {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Delayed {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.due, self.sequence).cmp(&(other.due, other.sequence))
+    }
+}
+
+/// A `NetworkConditioner` attached to a `Host`, with the datagrams it holds back.
+///
+/// ENet cannot be handed a received datagram later on, so for every delayed incoming datagram that is due,
+/// a wake-up datagram of a single byte is sent to the host's socket from a loopback socket. The intercept callback
+/// replaces it with the held back datagram before anything else sees it, so that datagram never leaves the process.
+#[derive(Debug)]
+pub(crate) struct Conditioner {
+    conditioner: NetworkConditioner,
+    delayed: BinaryHeap<Reverse<Delayed>>,
+    next_sequence: u64,
+    injector: UdpSocket,
+    injector_address: Address,
+    host_address: SocketAddr,
+    // datagrams that are due, with their sender, in the order of their wake-up datagrams
+    injected: VecDeque<(Address, Vec<u8>)>,
+}
+
+/// A `Conditioner`, shared by a `Host` with its `Socket`s and `InterceptContext`s.
+pub(crate) type SharedConditioner = Arc<Mutex<Conditioner>>;
+
+/// What the conditioner decided about a received datagram, see `Conditioner::receive`.
+pub(crate) enum Reception {
+    /// The datagram is handled right away.
+    Deliver,
+    /// The datagram is a wake-up sent by the conditioner, and has to be replaced by this held back datagram,
+    /// received from this address.
+    Replace(Address, Vec<u8>),
+    /// The datagram is lost, held back or not valid.
+    Withhold,
+}
+
+impl Conditioner {
+    /// Attaches `conditioner` to a host whose socket is bound to `host_address`.
+    pub(crate) fn new(conditioner: NetworkConditioner, host_address: Address) -> io::Result<Conditioner> {
+        // an unspecified address means the host is reachable on any local address, including loopback
+        let host_ip = match host_address.ip() {
+            ip if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            ip => ip,
+        };
+        let host_address = SocketAddr::new(host_ip, host_address.port());
+
+        let injector = UdpSocket::bind(SocketAddr::new(host_ip, 0))?;
+        let injector_address = Address(injector.local_addr()?);
+
+        Ok(Conditioner {
+            conditioner,
+            delayed: BinaryHeap::new(),
+            next_sequence: 0,
+            injector,
+            injector_address,
+            host_address,
+            injected: VecDeque::new(),
+        })
+    }
+
+    pub(crate) fn lock(shared: &SharedConditioner) -> MutexGuard<'_, Conditioner> {
+        // nothing panics while holding the lock, but the state is consistent in any case
+        shared.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Decides what happens to a datagram received from `address`.
+    pub(crate) fn receive(&mut self, address: Address, data: &[u8], now: Instant) -> Reception {
+        if address == self.injector_address {
+            return match self.injected.pop_front() {
+                Some((sender, data)) => Reception::Replace(sender, data),
+                None => Reception::Withhold,
+            };
+        }
+
+        if self.enter(Direction::Incoming, address, data, now) {
+            Reception::Deliver
+        } else {
+            Reception::Withhold
+        }
+    }
+
+    /// Sends the concatenation of `buffers` to `address` through the simulated network.
+    ///
+    /// Lost datagrams are reported as sent, like the socket of a real network would.
+    pub(crate) fn send(&mut self, socket: ENetSocket, address: &Address, buffers: &[&[u8]]) -> Result<u32, Error> {
+        let data = buffers.concat();
+
+        if self.enter(Direction::Outgoing, *address, &data, Instant::now()) {
+            socket::send_raw(socket, address, &[&data])
+        } else {
+            Ok(data.len() as u32)
+        }
+    }
+
+    /// Schedules the copies of a datagram, returning whether one of them is due right away,
+    /// in which case the caller has to deliver it.
+    fn enter(&mut self, direction: Direction, address: Address, data: &[u8], now: Instant) -> bool {
+        let mut deliver_now = false;
+
+        for due in self.conditioner.schedule(direction, data.len(), now) {
+            if due <= now && !deliver_now {
+                deliver_now = true;
+                continue;
+            }
+
+            self.delayed.push(Reverse(Delayed {
+                due,
+                sequence: self.next_sequence,
+                direction,
+                address,
+                data: data.to_vec(),
+            }));
+            self.next_sequence += 1;
+        }
+
+        if !deliver_now {
+            debug!("network conditioner withheld a datagram of {} bytes for {}", data.len(), address);
+        }
+
+        deliver_now
+    }
+
+    /// Delivers all held back datagrams that are due by `now`, sending outgoing ones through `socket`,
+    /// and returns when the next one is due.
+    pub(crate) fn release(&mut self, socket: ENetSocket, now: Instant) -> Option<Instant> {
+        while let Some(Reverse(delayed)) = self.delayed.peek() {
+            if delayed.due > now {
+                return Some(delayed.due);
+            }
+
+            let Reverse(delayed) = self.delayed.pop().expect("peeked above");
+
+            match delayed.direction {
+                Direction::Incoming => {
+                    if let Err(err) = self.injector.send_to(&[0], self.host_address) {
+                        debug!("network conditioner could not inject a datagram from {}: {}", delayed.address, err);
+                        continue;
+                    }
+                    self.injected.push_back((delayed.address, delayed.data));
+                }
+                Direction::Outgoing => {
+                    if let Err(err) = socket::send_raw(socket, &delayed.address, &[&delayed.data]) {
+                        debug!("network conditioner could not send a datagram to {}: {}", delayed.address, err);
+                    }
+                }
+            }
+        }
+
+        None
+    }
+}
+
+/// The SplitMix64 generator: tiny, fast, and good enough to simulate a network, with an output that
+/// only depends on the seed on every platform.
+#[derive(Debug, Clone)]
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a number between 0.0 (inclusive) and 1.0 (exclusive).
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.next_f64() < probability
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+        time::{Duration, Instant},
+    };
+
+    use super::{Direction, NetworkConditioner};
+    use crate::{
+        tests::{connect_hosts, localhost, service_until, ENET},
+        Address, BandwidthLimit, Event, Packet, PacketMode,
+    };
+
+    #[test]
+    fn test_schedule_is_reproducible() {
+        let conditioner = NetworkConditioner::with_seed(42)
+            .packet_loss(0.2)
+            .latency(Duration::from_millis(50))
+            .jitter(Duration::from_millis(20))
+            .duplication(0.1)
+            .reordering(0.1);
+
+        let now = Instant::now();
+        let schedule = |mut conditioner: NetworkConditioner| {
+            (0..100)
+                .map(|_| conditioner.schedule(Direction::Incoming, 100, now))
+                .collect::<Vec<_>>()
+        };
+
+        let first = schedule(conditioner.clone());
+        assert_eq!(first, schedule(conditioner));
+
+        assert!(first.iter().any(Vec::is_empty));
+        assert!(first.iter().any(|copies| copies.len() == 2));
+        for due in first.iter().flatten() {
+            assert!(*due >= now + Duration::from_millis(50));
+        }
+
+        // 1000 bytes/second: the second datagram of 500 bytes waits for the first one
+        let mut limited = NetworkConditioner::with_seed(0).bandwidth_limit(BandwidthLimit::Limited(1000));
+        assert_eq!(limited.schedule(Direction::Outgoing, 500, now), [now + Duration::from_millis(500)]);
+        assert_eq!(limited.schedule(Direction::Outgoing, 500, now), [now + Duration::from_secs(1)]);
+        assert_eq!(limited.schedule(Direction::Incoming, 500, now), [now + Duration::from_millis(500)]);
+    }
+
+    #[test]
+    fn test_delayed_datagrams_keep_their_sender() {
+        let address = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 12376));
+        let mut host = ENET.host_builder().address(Address(address)).build::<()>().unwrap();
+
+        let latency = Duration::from_millis(100);
+        host.set_network_conditioner(NetworkConditioner::with_seed(1).latency(latency))
+            .unwrap();
+        host.register_oob_handler("ping", |context, payload| {
+            context.reply_oob("pong", payload).unwrap();
+        });
+
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+        // a datagram as large as ENet's receive buffer is delivered in full
+        let mut large = b"\xFF\xFF\xFF\xFFping ".to_vec();
+        large.resize(4096, b'x');
+
+        let sent_at = Instant::now();
+        socket.send_to(b"\xFF\xFF\xFF\xFFping 1", address).unwrap();
+        socket.send_to(&large, address).unwrap();
+
+        // the requests are held back on the way in, and the replies on the way out
+        while sent_at.elapsed() < 3 * latency {
+            assert!(host.service(10).unwrap().is_none());
+        }
+
+        let mut buffer = [0; 4097];
+        let (len, from) = socket.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"\xFF\xFF\xFF\xFFpong\n1");
+        assert_eq!(from, address);
+
+        let (len, _) = socket.recv_from(&mut buffer).unwrap();
+        assert_eq!(len, large.len());
+        assert_eq!(&buffer[..9], b"\xFF\xFF\xFF\xFFpong\n");
+        assert_eq!(&buffer[9..len], &large[9..]);
+    }
+
+    #[test]
+    fn test_enet_datagrams_are_conditioned_on_receipt() {
+        let address = localhost(12389);
+        let mut server = ENET.host_builder().address(address).build::<()>().unwrap();
+        let mut client = ENET.host_builder().address(localhost(0)).build::<()>().unwrap();
+        let (peer, _) = connect_hosts(&mut server, &mut client, &address);
+
+        // ENet sends its datagrams itself, so they are conditioned by the host that receives them
+        let latency = Duration::from_millis(150);
+        client
+            .set_network_conditioner(NetworkConditioner::with_seed(1).latency(latency))
+            .unwrap();
+
+        for (data, delayed) in [(&b"delayed"[..], true), (b"prompt", false)] {
+            let packet = Packet::new(data, PacketMode::ReliableSequenced).unwrap();
+            server.peer_mut(peer).unwrap().send_packet(packet, 0).unwrap();
+            let sent_at = Instant::now();
+            server.flush();
+
+            let received = service_until(Duration::from_secs(1), || {
+                server.service(0).unwrap();
+                match client.service(1).unwrap() {
+                    Some(Event::Receive { ref packet, .. }) => Some(packet.data().to_vec()),
+                    _ => None,
+                }
+            });
+            assert_eq!(received.as_deref(), Some(data));
+            assert_eq!(sent_at.elapsed() >= latency, delayed);
+
+            client.clear_network_conditioner();
+        }
+
+        assert_eq!(server.peer(peer).unwrap().address(), client.local_addr().unwrap());
+    }
+}
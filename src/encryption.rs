@@ -0,0 +1,872 @@
+use std::{
+    collections::VecDeque,
+    fmt, mem,
+    os::raw::c_int,
+    ptr, slice,
+    time::{Duration, Instant},
+};
+
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, Key, KeyInit, Nonce};
+use log::debug;
+use snow::{params::NoiseParams, Builder, HandshakeState};
+
+use citizen_enet_sys::{
+    enet_packet_create, enet_packet_destroy, enet_peer_disconnect, enet_peer_disconnect_now, enet_peer_send,
+    ENetEvent, ENetHost, ENetPacket, ENetPeer, _ENetEventType_ENET_EVENT_TYPE_CONNECT,
+    _ENetEventType_ENET_EVENT_TYPE_DISCONNECT, _ENetEventType_ENET_EVENT_TYPE_RECEIVE,
+    _ENetPacketFlag_ENET_PACKET_FLAG_NO_ALLOCATE, _ENetPacketFlag_ENET_PACKET_FLAG_RELIABLE,
+};
+
+/// The user data of the disconnection of a peer whose handshake or packets failed, see `Host::set_encryption`.
+pub const ENCRYPTION_FAILED: u32 = 0x4352_5950;
+
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+// binds the handshake to this protocol, in addition to the connection it runs on
+const PROLOGUE: &[u8] = b"citizen-enet encryption v1";
+const MAX_HANDSHAKE_MESSAGE_LEN: usize = 1024;
+
+// handshake messages have to arrive in order
+const RELIABLE: u32 = _ENetPacketFlag_ENET_PACKET_FLAG_RELIABLE;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+const COUNTER_LEN: usize = 8;
+const TAG_LEN: usize = 16;
+const CHANNEL_COUNT: usize = 256;
+const REPLAY_WINDOW_LEN: u64 = 64;
+
+/// The number of packets held back per peer until its handshake completed, beyond which the peer is disconnected.
+const MAX_HELD_PACKETS: usize = 256;
+
+/// Encrypts the packets exchanged by a `Host` with its peers, see `Host::set_encryption`.
+///
+/// Right after a peer connected, both hosts run a Noise XX handshake (`Noise_XX_25519_ChaChaPoly_BLAKE2s`)
+/// on a reserved channel, which authenticates their static keys to each other. Afterwards, every packet is
+/// encrypted with ChaCha20-Poly1305, using the keys of the handshake and a nonce of the channel ID and a
+/// counter per channel, which is sent along with the packet (adding 24 bytes to it).
+///
+/// The handshake does not check who owns the remote static key; compare `Host::peer_public_key` with the key
+/// you expect to prevent an attacker from intercepting the connection.
+#[derive(Clone)]
+pub struct Encryption {
+    private_key: Vec<u8>,
+    public_key: Vec<u8>,
+    channel_id: u8,
+    timeout: Duration,
+}
+
+impl Encryption {
+    /// Creates an encryption with a newly generated static key pair, running its handshake on channel `channel_id`.
+    ///
+    /// The channel is reserved for the handshake, so peers have to be connected with enough channels to include it,
+    /// and packets cannot be sent on it.
+    pub fn new(channel_id: u8) -> Self {
+        let keypair = Builder::new(noise_params())
+            .generate_keypair()
+            .expect("could not generate a key pair");
+
+        Encryption::with_keypair(&keypair.private, &keypair.public, channel_id)
+    }
+
+    /// Creates an encryption with the given static X25519 key pair, see `Encryption::new`.
+    pub fn with_keypair(private_key: &[u8], public_key: &[u8], channel_id: u8) -> Self {
+        Encryption {
+            private_key: private_key.to_vec(),
+            public_key: public_key.to_vec(),
+            channel_id,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Sets how long the handshake with a connected peer may take, 5 seconds by default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Returns the static public key of this encryption, which peers see through `Host::peer_public_key`.
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    /// Returns the channel reserved for the handshake.
+    pub fn channel_id(&self) -> u8 {
+        self.channel_id
+    }
+}
+
+impl fmt::Debug for Encryption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // never print the private key
+        f.debug_struct("Encryption")
+            .field("public_key", &self.public_key)
+            .field("channel_id", &self.channel_id)
+            .field("timeout", &self.timeout)
+            .finish_non_exhaustive()
+    }
+}
+
+fn noise_params() -> NoiseParams {
+    NOISE_PARAMS.parse().expect("invalid noise parameters")
+}
+
+/// Tracks the counters received on a channel, to reject replayed packets.
+#[derive(Debug, Default, Clone, Copy)]
+struct ReplayWindow {
+    // the highest counter received so far, plus one
+    next: u64,
+    // bit `i` is set if `next - 1 - i` was received
+    received: u64,
+}
+
+impl ReplayWindow {
+    fn is_fresh(&self, counter: u64) -> bool {
+        if counter >= self.next {
+            return true;
+        }
+
+        let age = self.next - 1 - counter;
+        age < REPLAY_WINDOW_LEN && self.received & (1 << age) == 0
+    }
+
+    fn mark(&mut self, counter: u64) {
+        if counter >= self.next {
+            let shift = counter - self.next + 1;
+            self.received = if shift >= REPLAY_WINDOW_LEN { 0 } else { self.received << shift };
+            self.received |= 1;
+            self.next = counter + 1;
+        } else {
+            self.received |= 1 << (self.next - 1 - counter);
+        }
+    }
+}
+
+enum Opened {
+    Plaintext(Vec<u8>),
+    /// The packet was received before, or is too old to tell, and is dropped.
+    Replayed,
+    /// The packet failed authentication.
+    Forged,
+}
+
+/// The ciphers of a peer whose handshake completed.
+struct Transport {
+    sender: ChaCha20Poly1305,
+    receiver: ChaCha20Poly1305,
+    send_counters: Vec<u64>,
+    replay_windows: Vec<ReplayWindow>,
+    remote_public_key: Vec<u8>,
+}
+
+impl Transport {
+    fn new(handshake: &mut HandshakeState) -> Self {
+        let remote_public_key = handshake.get_remote_static().unwrap_or_default().to_vec();
+        let (initiator_key, responder_key) = handshake.dangerously_get_raw_split();
+        let (send_key, receive_key) = if handshake.is_initiator() {
+            (initiator_key, responder_key)
+        } else {
+            (responder_key, initiator_key)
+        };
+
+        Transport {
+            sender: ChaCha20Poly1305::new(Key::from_slice(&send_key)),
+            receiver: ChaCha20Poly1305::new(Key::from_slice(&receive_key)),
+            send_counters: vec![0; CHANNEL_COUNT],
+            replay_windows: vec![ReplayWindow::default(); CHANNEL_COUNT],
+            remote_public_key,
+        }
+    }
+
+    fn seal(&mut self, channel_id: u8, plaintext: &[u8]) -> Option<Vec<u8>> {
+        let counter = self.send_counters[channel_id as usize];
+        self.send_counters[channel_id as usize] += 1;
+
+        let mut sealed = Vec::with_capacity(COUNTER_LEN + plaintext.len() + TAG_LEN);
+        sealed.extend_from_slice(&counter.to_be_bytes());
+        sealed.extend(self.sender.encrypt(&nonce(channel_id, counter), plaintext).ok()?);
+        Some(sealed)
+    }
+
+    fn open(&mut self, channel_id: u8, sealed: &[u8]) -> Opened {
+        if sealed.len() < COUNTER_LEN + TAG_LEN {
+            return Opened::Forged;
+        }
+
+        let (counter, ciphertext) = sealed.split_at(COUNTER_LEN);
+        let counter = u64::from_be_bytes(counter.try_into().expect("counter length"));
+
+        let window = &mut self.replay_windows[channel_id as usize];
+        if !window.is_fresh(counter) {
+            return Opened::Replayed;
+        }
+
+        match self.receiver.decrypt(&nonce(channel_id, counter), ciphertext) {
+            Ok(plaintext) => {
+                window.mark(counter);
+                Opened::Plaintext(plaintext)
+            }
+            Err(_) => Opened::Forged,
+        }
+    }
+}
+
+/// Builds the nonce for a packet, from the channel it is sent on and its counter in that channel.
+fn nonce(channel_id: u8, counter: u64) -> Nonce {
+    let mut nonce = Nonce::default();
+    nonce[3] = channel_id;
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
+enum Stage {
+    Handshaking(Box<HandshakeState>),
+    Established(Box<Transport>),
+    /// The peer is being disconnected, and all its packets are dropped.
+    Failed,
+}
+
+/// A packet received before the handshake completed, to be decrypted and delivered after the `Connect` event.
+struct Held {
+    channel_id: u8,
+    flags: u32,
+    data: Vec<u8>,
+}
+
+struct Session {
+    connect_id: u32,
+    initiator: bool,
+    stage: Stage,
+    // whether the application got the `Connect` event
+    connected: bool,
+    deadline: Option<Instant>,
+    held: Vec<Held>,
+}
+
+/// The state of a `Host`'s `Encryption`, which holds back the events of peers until their handshake completed,
+/// and encrypts and decrypts their packets afterwards.
+pub(crate) struct Encryptor {
+    config: Encryption,
+    // connect IDs of the connections initiated by `Host::connect`, per peer slot
+    initiated: Vec<u32>,
+    sessions: Vec<Option<Session>>,
+    // held packets of peers that completed their handshake, by peer slot, see `Encryptor::release`
+    released: VecDeque<(usize, u32, Held)>,
+}
+
+impl Encryptor {
+    /// Creates the encryptor of `host`.
+    pub(crate) fn new(host: *mut ENetHost, config: Encryption) -> Encryptor {
+        let peer_count = unsafe { (*host).peerCount };
+
+        Encryptor {
+            config,
+            initiated: vec![0; peer_count],
+            sessions: (0..peer_count).map(|_| None).collect(),
+            released: VecDeque::new(),
+        }
+    }
+
+    /// Records that the connection `connect_id` in slot `index` was initiated by this host.
+    pub(crate) fn connecting(&mut self, index: usize, connect_id: u32) {
+        self.initiated[index] = connect_id;
+    }
+
+    /// Returns the static public key of the peer with `connect_id` in slot `index`, once its handshake completed.
+    pub(crate) fn peer_public_key(&self, index: usize, connect_id: u32) -> Option<Vec<u8>> {
+        match &self.sessions[index] {
+            Some(Session {
+                connect_id: session_connect_id,
+                stage: Stage::Established(transport),
+                ..
+            }) if *session_connect_id == connect_id => Some(transport.remote_public_key.clone()),
+            _ => None,
+        }
+    }
+
+    /// Gives up on the peers whose handshake did not complete in time, see `give_up`.
+    pub(crate) unsafe fn expire(&mut self, host: *mut ENetHost, now: Instant) {
+        for index in 0..self.sessions.len() {
+            let session = match &self.sessions[index] {
+                Some(session) if session.deadline.is_some_and(|deadline| deadline <= now) => session,
+                _ => continue,
+            };
+
+            let peer = (*host).peers.add(index);
+            if (*peer).connectID != session.connect_id {
+                // the peer was reset by the application in the meantime
+                self.sessions[index] = None;
+                continue;
+            }
+
+            debug!("handshake with peer {} did not complete in time", index);
+            self.give_up(peer, index);
+        }
+    }
+
+    /// Handles `sys_event` and returns whether it is delivered to the application.
+    ///
+    /// Events of peers whose handshake did not complete are withheld, except for the `Disconnect` of a connection
+    /// this host initiated, and received packets are decrypted. When the handshake of a peer completes, its event is
+    /// turned into the `Connect` event the application did not get yet, and the packets held back for the peer are
+    /// released.
+    pub(crate) unsafe fn filter(&mut self, sys_event: &mut ENetEvent, connect_id: u32) -> bool {
+        if sys_event.peer.is_null() {
+            return true;
+        }
+
+        let peer = sys_event.peer;
+        let index = (*peer).incomingPeerID as usize;
+        if !matches!(&self.sessions[index], Some(session) if session.connect_id == connect_id) {
+            if sys_event.type_ == _ENetEventType_ENET_EVENT_TYPE_CONNECT {
+                self.begin(peer, index, connect_id);
+                return false;
+            }
+
+            // the connection predates the encryption
+            return true;
+        }
+
+        #[allow(non_upper_case_globals)]
+        match sys_event.type_ {
+            _ENetEventType_ENET_EVENT_TYPE_DISCONNECT => {
+                let session = self.sessions[index].take().expect("session");
+                self.released.retain(|(released_index, ..)| *released_index != index);
+                session.connected || session.initiator
+            }
+            _ENetEventType_ENET_EVENT_TYPE_RECEIVE => {
+                let packet = sys_event.packet;
+                let data = packet_data(packet);
+
+                let deliver = if sys_event.channelID == self.config.channel_id {
+                    let connected = self.handle_handshake(peer, index, data);
+                    if connected {
+                        sys_event.type_ = _ENetEventType_ENET_EVENT_TYPE_CONNECT;
+                        sys_event.channelID = 0;
+                        sys_event.data = (*peer).eventData;
+                        sys_event.packet = ptr::null_mut();
+                    }
+                    connected
+                } else {
+                    match self.open(peer, index, sys_event.channelID, (*packet).flags, data) {
+                        Some(plaintext) => {
+                            sys_event.packet = create_packet(&plaintext, (*packet).flags);
+                            !sys_event.packet.is_null()
+                        }
+                        None => false,
+                    }
+                };
+
+                enet_packet_destroy(packet);
+                deliver
+            }
+            _ => true,
+        }
+    }
+
+    /// Starts the handshake with a newly connected peer, sending the first message if this host initiated it.
+    unsafe fn begin(&mut self, peer: *mut ENetPeer, index: usize, connect_id: u32) {
+        let initiator = self.initiated[index] == connect_id;
+
+        let mut prologue = PROLOGUE.to_vec();
+        prologue.extend_from_slice(&connect_id.to_be_bytes());
+
+        let builder = Builder::new(noise_params())
+            .local_private_key(&self.config.private_key)
+            .prologue(&prologue);
+        let handshake = if initiator {
+            builder.build_initiator()
+        } else {
+            builder.build_responder()
+        };
+
+        self.sessions[index] = Some(Session {
+            connect_id,
+            initiator,
+            stage: Stage::Failed,
+            connected: false,
+            deadline: Some(Instant::now() + self.config.timeout),
+            held: Vec::new(),
+        });
+
+        let mut handshake = match handshake {
+            Ok(handshake) => Box::new(handshake),
+            Err(err) => {
+                debug!("could not start the handshake with peer {}: {}", index, err);
+                self.give_up(peer, index);
+                return;
+            }
+        };
+
+        if initiator && !self.write_handshake(peer, &mut handshake) {
+            self.give_up(peer, index);
+            return;
+        }
+
+        self.session_mut(index).stage = Stage::Handshaking(handshake);
+    }
+
+    /// Handles a message on the reserved channel, and returns whether the peer is connected by it.
+    unsafe fn handle_handshake(&mut self, peer: *mut ENetPeer, index: usize, data: &[u8]) -> bool {
+        let channel_id = self.config.channel_id;
+        let session = self.session_mut(index);
+        if session.connected {
+            return false;
+        }
+
+        match mem::replace(&mut session.stage, Stage::Failed) {
+            Stage::Handshaking(mut handshake) => {
+                let mut payload = [0; MAX_HANDSHAKE_MESSAGE_LEN];
+                if let Err(err) = handshake.read_message(data, &mut payload) {
+                    debug!("handshake with peer {} failed: {}", index, err);
+                    self.give_up(peer, index);
+                    return false;
+                }
+
+                if !handshake.is_handshake_finished() && !self.write_handshake(peer, &mut handshake) {
+                    self.give_up(peer, index);
+                    return false;
+                }
+
+                // the initiator finishes by writing the last message, the responder by reading it
+                if !handshake.is_handshake_finished() {
+                    self.session_mut(index).stage = Stage::Handshaking(handshake);
+                    return false;
+                }
+
+                let mut transport = Box::new(Transport::new(&mut handshake));
+                let session = self.session_mut(index);
+                if session.initiator {
+                    // waits for the responder's confirmation, as packets sent before it is ready would be lost
+                    session.stage = Stage::Established(transport);
+                    return false;
+                }
+
+                let confirmation = transport.seal(channel_id, &[]);
+                session.stage = Stage::Established(transport);
+                match confirmation {
+                    Some(confirmation) if send_packet(peer, channel_id, &confirmation, RELIABLE) == 0 => {
+                        self.connect(index);
+                        true
+                    }
+                    _ => {
+                        self.give_up(peer, index);
+                        false
+                    }
+                }
+            }
+            Stage::Established(mut transport) if session.initiator => {
+                let confirmation = transport.open(channel_id, data);
+                session.stage = Stage::Established(transport);
+
+                match confirmation {
+                    Opened::Plaintext(plaintext) if plaintext.is_empty() => {
+                        self.connect(index);
+                        true
+                    }
+                    _ => {
+                        self.give_up(peer, index);
+                        false
+                    }
+                }
+            }
+            stage => {
+                session.stage = stage;
+                false
+            }
+        }
+    }
+
+    /// Marks the peer in slot `index` as connected, releasing the packets held back for it.
+    fn connect(&mut self, index: usize) {
+        let session = self.sessions[index].as_mut().expect("session");
+        session.connected = true;
+        session.deadline = None;
+
+        let connect_id = session.connect_id;
+        self.released.extend(session.held.drain(..).map(|held| (index, connect_id, held)));
+    }
+
+    /// Decrypts a packet received on an application channel, or holds it back until the handshake completed.
+    unsafe fn open(
+        &mut self,
+        peer: *mut ENetPeer,
+        index: usize,
+        channel_id: u8,
+        flags: u32,
+        data: &[u8],
+    ) -> Option<Vec<u8>> {
+        let session = self.session_mut(index);
+
+        match &mut session.stage {
+            Stage::Established(transport) if session.connected => match transport.open(channel_id, data) {
+                Opened::Plaintext(plaintext) => Some(plaintext),
+                Opened::Replayed => None,
+                Opened::Forged => {
+                    debug!("packet from peer {} failed authentication", index);
+                    self.give_up(peer, index);
+                    None
+                }
+            },
+            Stage::Failed => None,
+            _ if session.held.len() < MAX_HELD_PACKETS => {
+                session.held.push(Held {
+                    channel_id,
+                    flags,
+                    data: data.to_vec(),
+                });
+                None
+            }
+            _ => {
+                debug!("peer {} sent too many packets during its handshake", index);
+                self.give_up(peer, index);
+                None
+            }
+        }
+    }
+
+    /// Returns the next packet held back for a peer that completed its handshake since, as a `Receive` event
+    /// with the connect ID of the peer.
+    pub(crate) unsafe fn release(&mut self, host: *mut ENetHost) -> Option<(ENetEvent, u32)> {
+        while let Some((index, connect_id, held)) = self.released.pop_front() {
+            let peer = (*host).peers.add(index);
+
+            let plaintext = match &mut self.sessions[index] {
+                Some(Session {
+                    stage: Stage::Established(transport),
+                    ..
+                }) => transport.open(held.channel_id, &held.data),
+                _ => continue,
+            };
+
+            match plaintext {
+                Opened::Plaintext(plaintext) => {
+                    let packet = create_packet(&plaintext, held.flags);
+                    if packet.is_null() {
+                        continue;
+                    }
+
+                    let sys_event = ENetEvent {
+                        type_: _ENetEventType_ENET_EVENT_TYPE_RECEIVE,
+                        peer,
+                        channelID: held.channel_id,
+                        data: 0,
+                        packet,
+                    };
+                    return Some((sys_event, connect_id));
+                }
+                Opened::Replayed => (),
+                Opened::Forged => {
+                    debug!("packet from peer {} failed authentication", index);
+                    self.give_up(peer, index);
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Queues `packet` to be sent to `peer` like `enet_peer_send`, encrypting it if the connection is encrypted.
+    /// Unlike `enet_peer_send`, the packet is never taken over if it is encrypted, so the caller has to destroy it
+    /// if it is not referenced afterwards.
+    pub(crate) unsafe fn send(&mut self, peer: *mut ENetPeer, channel_id: u8, packet: *mut ENetPacket) -> c_int {
+        let index = (*peer).incomingPeerID as usize;
+
+        let session = match &mut self.sessions[index] {
+            Some(session) if session.connect_id == (*peer).connectID => session,
+            // the connection predates the encryption
+            _ => return enet_peer_send(peer, channel_id, packet),
+        };
+
+        let transport = match &mut session.stage {
+            Stage::Established(transport) if channel_id != self.config.channel_id => transport,
+            _ => return -1,
+        };
+
+        let sealed = match transport.seal(channel_id, packet_data(packet)) {
+            Some(sealed) => sealed,
+            None => return -1,
+        };
+
+        send_packet(peer, channel_id, &sealed, (*packet).flags)
+    }
+
+    /// Decrypts `packet`, received from `peer` through `enet_peer_receive`, if the connection is encrypted.
+    ///
+    /// Returns the decrypted packet, or null if the packet was dropped. `packet` is destroyed if it is not returned.
+    pub(crate) unsafe fn receive(
+        &mut self,
+        peer: *mut ENetPeer,
+        channel_id: u8,
+        packet: *mut ENetPacket,
+    ) -> *mut ENetPacket {
+        let index = (*peer).incomingPeerID as usize;
+
+        if !matches!(&self.sessions[index], Some(session) if session.connect_id == (*peer).connectID) {
+            // the connection predates the encryption
+            return packet;
+        }
+
+        let plaintext = if channel_id == self.config.channel_id {
+            None
+        } else {
+            self.open(peer, index, channel_id, (*packet).flags, packet_data(packet))
+        };
+
+        let flags = (*packet).flags;
+        enet_packet_destroy(packet);
+        plaintext.map_or(ptr::null_mut(), |plaintext| create_packet(&plaintext, flags))
+    }
+
+    unsafe fn write_handshake(&self, peer: *mut ENetPeer, handshake: &mut HandshakeState) -> bool {
+        let mut message = [0; MAX_HANDSHAKE_MESSAGE_LEN];
+
+        match handshake.write_message(&[], &mut message) {
+            Ok(len) => send_packet(peer, self.config.channel_id, &message[..len], RELIABLE) == 0,
+            Err(err) => {
+                debug!("could not write a handshake message: {}", err);
+                false
+            }
+        }
+    }
+
+    /// Gives up on the peer in slot `index`.
+    ///
+    /// Peers the application does not know about yet are reset immediately, while other peers are disconnected,
+    /// so the application gets the `Disconnect` event for them.
+    unsafe fn give_up(&mut self, peer: *mut ENetPeer, index: usize) {
+        self.released.retain(|(released_index, ..)| *released_index != index);
+
+        let session = self.session_mut(index);
+        if session.connected || session.initiator {
+            enet_peer_disconnect(peer, ENCRYPTION_FAILED);
+            session.stage = Stage::Failed;
+            session.deadline = None;
+            session.held.clear();
+        } else {
+            enet_peer_disconnect_now(peer, ENCRYPTION_FAILED);
+            self.sessions[index] = None;
+        }
+    }
+
+    fn session_mut(&mut self, index: usize) -> &mut Session {
+        self.sessions[index].as_mut().expect("session")
+    }
+}
+
+unsafe fn packet_data<'a>(packet: *mut ENetPacket) -> &'a [u8] {
+    // ENet does not allocate any data for empty packets
+    if (*packet).data.is_null() {
+        return &[];
+    }
+
+    slice::from_raw_parts((*packet).data, (*packet).dataLength)
+}
+
+/// Creates a packet with a copy of `data`, with the flags of the packet it replaces.
+unsafe fn create_packet(data: &[u8], flags: u32) -> *mut ENetPacket {
+    let flags = flags & !_ENetPacketFlag_ENET_PACKET_FLAG_NO_ALLOCATE;
+    enet_packet_create(data.as_ptr() as *const _, data.len(), flags)
+}
+
+unsafe fn send_packet(peer: *mut ENetPeer, channel_id: u8, data: &[u8], flags: u32) -> c_int {
+    let packet = create_packet(data, flags);
+    if packet.is_null() {
+        return -1;
+    }
+
+    let res = enet_peer_send(peer, channel_id, packet);
+    if res < 0 {
+        enet_packet_destroy(packet);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+
+    use super::{Encryption, ReplayWindow, ENCRYPTION_FAILED};
+    use crate::{
+        protocol::{Command, CommandBody, Datagram, Header, UNASSIGNED_PEER_ID},
+        tests::{connect_hosts, localhost, service_until, ENET},
+        Event, InterceptVerdict, OwnedEvent, Packet, PacketMode,
+    };
+
+    #[test]
+    fn test_replay_window() {
+        let mut window = ReplayWindow::default();
+
+        for counter in [0, 2, 1, 70] {
+            assert!(window.is_fresh(counter));
+            window.mark(counter);
+            assert!(!window.is_fresh(counter));
+        }
+
+        // too old to tell whether it was received
+        assert!(!window.is_fresh(3));
+        assert!(window.is_fresh(69));
+    }
+
+    #[test]
+    fn test_encrypted_connection() {
+        let address = localhost(12379);
+        let server_encryption = Encryption::new(1);
+        let client_encryption = Encryption::new(1);
+
+        let mut server = ENET.host_builder().address(address).build::<()>().unwrap();
+        server.set_encryption(server_encryption.clone());
+        let plaintext_seen = Arc::new(AtomicBool::new(false));
+        let seen = plaintext_seen.clone();
+        server.set_intercept(move |context| {
+            if context.data().windows(6).any(|window| window == b"secret") {
+                seen.store(true, Ordering::SeqCst);
+            }
+            InterceptVerdict::Pass
+        });
+
+        let mut client = ENET.host_builder().build::<()>().unwrap();
+        client.set_encryption(client_encryption.clone());
+        let (_, peer) = connect_hosts(&mut server, &mut client, &address);
+        assert_eq!(client.peer_public_key(peer).unwrap(), server_encryption.public_key());
+
+        let mut p = client.peer_mut(peer).unwrap();
+        assert!(p.send_packet(Packet::new(b"hello", PacketMode::ReliableSequenced).unwrap(), 1).is_err());
+        for data in [&b"secret"[..], b"second", b"third"] {
+            p.send_packet(Packet::new(data, PacketMode::ReliableSequenced).unwrap(), 0).unwrap();
+        }
+        client.flush();
+
+        let (sender, packet) = service_until(Duration::from_secs(1), || {
+            match server.service(5).unwrap().map(OwnedEvent::from) {
+                Some(OwnedEvent::Receive { peer, packet, .. }) => Some((peer, packet)),
+                None => None,
+                Some(event) => panic!("unexpected event: {:?}", event),
+            }
+        })
+        .expect("receiving timed out");
+
+        assert_eq!(packet.data(), b"secret");
+        assert_eq!(server.peer_public_key(sender).unwrap(), client_encryption.public_key());
+        assert!(!plaintext_seen.load(Ordering::SeqCst));
+
+        // the other packets arrived in the same datagram, and are still queued for the peer
+        let mut sender = server.peer_mut(sender).unwrap();
+        for data in [&b"second"[..], b"third"] {
+            let received = sender.receive().expect("packet not queued");
+            assert_eq!((received.channel_id, received.packet.data()), (0, data));
+        }
+        assert!(sender.receive().is_none());
+    }
+
+    #[test]
+    fn test_packets_held_back_during_handshake() {
+        let address = localhost(12381);
+        let mut server = ENET.host_builder().address(address).build::<()>().unwrap();
+        server.set_encryption(Encryption::new(1));
+
+        // loses the datagram with the server's confirmation of the handshake (its second message on the
+        // reserved channel) once, so a packet sent after it arrives first
+        let mut client = ENET.host_builder().build::<()>().unwrap();
+        client.set_encryption(Encryption::new(1));
+        let handshake_datagrams = AtomicUsize::new(0);
+        client.set_intercept(move |context| {
+            let handshake = Datagram::parse(context.data()).is_ok_and(|datagram| {
+                datagram.commands.iter().any(|command| {
+                    command.channel_id == 1 && matches!(command.body, CommandBody::SendReliable { .. })
+                })
+            });
+
+            if handshake && handshake_datagrams.fetch_add(1, Ordering::SeqCst) == 1 {
+                InterceptVerdict::Drop
+            } else {
+                InterceptVerdict::Pass
+            }
+        });
+
+        let peer = client.connect(&address, 2, 0).unwrap();
+        let mut events = Vec::new();
+
+        service_until(Duration::from_secs(2), || {
+            let connected = match server.service(0).unwrap() {
+                Some(Event::Connect(ref peer)) => Some(peer.id()),
+                _ => None,
+            };
+            if let Some(connected) = connected {
+                // sends the confirmation before the packet, in its own datagram
+                server.flush();
+                let packet = Packet::new(b"early", PacketMode::ReliableSequenced).unwrap();
+                server.peer_mut(connected).unwrap().send_packet(packet, 0).unwrap();
+                server.flush();
+            }
+
+            if let Some(event) = client.service(5).unwrap() {
+                events.push(OwnedEvent::from(event));
+            }
+            Some(()).filter(|_| events.len() == 2)
+        })
+        .expect("receiving timed out");
+
+        match &events[..] {
+            [OwnedEvent::Connect { peer: connected, .. }, OwnedEvent::Receive { peer: sender, channel_id: 0, packet, .. }] => {
+                assert_eq!((*connected, *sender), (peer, peer));
+                assert_eq!(packet.data(), b"early");
+            }
+            other => panic!("unexpected events: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_forged_packet_disconnects() {
+        let address = localhost(12382);
+        let mut server = ENET.host_builder().address(address).build::<()>().unwrap();
+        server.set_encryption(Encryption::new(1));
+
+        // the peer and session IDs the client uses for the server
+        let last_header = Arc::new(Mutex::new(None));
+        let header = last_header.clone();
+        server.set_intercept(move |context| {
+            if let Ok((parsed, _)) = Header::parse(context.data()) {
+                if parsed.peer_id != UNASSIGNED_PEER_ID {
+                    *header.lock().unwrap() = Some(parsed);
+                }
+            }
+            InterceptVerdict::Pass
+        });
+
+        let mut client = ENET.host_builder().build::<()>().unwrap();
+        client.set_encryption(Encryption::new(1));
+        connect_hosts(&mut server, &mut client, &address);
+
+        let header = last_header.lock().unwrap().expect("no datagram from the client");
+        let forged = Datagram {
+            header: Header { sent_time: None, ..header },
+            checksum: None,
+            commands: vec![Command::new(
+                0,
+                0,
+                false,
+                CommandBody::SendUnsequenced {
+                    unsequenced_group: 1,
+                    data: &[0; 32],
+                },
+            )],
+        };
+        client.socket().send_data(&address, &forged.encode()).unwrap();
+
+        let data = service_until(Duration::from_secs(2), || {
+            server.service(0).unwrap();
+            match client.service(5).unwrap() {
+                Some(Event::Disconnect(_, data, _)) => Some(data),
+                Some(event) => panic!("unexpected event: {:?}", OwnedEvent::from(event)),
+                None => None,
+            }
+        });
+        assert_eq!(data, Some(ENCRYPTION_FAILED));
+    }
+}
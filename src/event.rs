@@ -3,7 +3,31 @@ use citizen_enet_sys::{
     _ENetEventType_ENET_EVENT_TYPE_NONE, _ENetEventType_ENET_EVENT_TYPE_RECEIVE,
 };
 
-use crate::{Packet, Peer};
+use std::{mem::ManuallyDrop, ptr};
+
+use crate::{host::Layers, Address, Packet, Peer, PeerId};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Why a peer disconnected, as reported by `Event::Disconnect`.
+///
+/// ENet itself does not report this, so it is derived from the state of the peer before the disconnection,
+/// and from how long its reliable data had gone unacknowledged.
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum DisconnectKind {
+    /// The foreign peer disconnected, with the data it passed to `Peer::disconnect`.
+    Graceful,
+    /// The connection timed out, e.g. because the foreign peer went away or the network dropped it.
+    /// The disconnect data is always 0.
+    Timeout,
+    /// The foreign peer reset or rejected the connection before it was established.
+    Reset,
+    /// The disconnection was requested locally through `Peer::disconnect` or `Peer::disconnect_later`.
+    /// Also used if the foreign peer did not acknowledge the request in time.
+    LocalRequest,
+}
 
 /// This enum represents an event that can occur when servicing an `EnetHost`.
 ///
@@ -15,8 +39,8 @@ pub enum Event<'a, T> {
     /// This variant represents the disconnection of a peer, either because it was requested or due to a timeout.
     ///
     /// The disconnected peer is contained in the first field, while the second field contains the user-specified
-    /// data for this disconnection.
-    Disconnect(Peer<'a, T>, u32),
+    /// data for this disconnection, and the third field why the disconnection happened.
+    Disconnect(Peer<'a, T>, u32, DisconnectKind),
     /// This variants repersents a packet that was received.
     Receive {
         /// The `Peer` that sent the packet.
@@ -28,26 +52,150 @@ pub enum Event<'a, T> {
     },
 }
 
+/// An event like `Event`, which does not borrow the `Host` it originated from.
+///
+/// Instead of a `Peer`, it carries the `PeerId` and address of the peer it belongs to,
+/// so it can be stored or sent to other threads.
+#[derive(Debug)]
+pub enum OwnedEvent {
+    /// A peer connected.
+    Connect {
+        /// The `PeerId` of the peer that connected.
+        peer: PeerId,
+        /// The address of the peer that connected.
+        address: Address,
+        /// The user-specified data the peer passed when connecting.
+        data: u32,
+    },
+    /// A peer disconnected, either because it was requested or due to a timeout.
+    Disconnect {
+        /// The `PeerId` of the peer that disconnected.
+        peer: PeerId,
+        /// The address of the peer that disconnected.
+        address: Address,
+        /// The user-specified data for this disconnection.
+        data: u32,
+        /// Why the disconnection happened.
+        kind: DisconnectKind,
+    },
+    /// A packet was received.
+    Receive {
+        /// The `PeerId` of the peer that sent the packet.
+        peer: PeerId,
+        /// The address of the peer that sent the packet.
+        address: Address,
+        /// The channel on which the packet was received.
+        channel_id: u8,
+        /// The `Packet` that was received.
+        packet: Packet,
+    },
+}
+
+impl OwnedEvent {
+    /// Returns the `PeerId` of the peer this event belongs to.
+    pub fn peer_id(&self) -> PeerId {
+        match self {
+            OwnedEvent::Connect { peer, .. }
+            | OwnedEvent::Disconnect { peer, .. }
+            | OwnedEvent::Receive { peer, .. } => *peer,
+        }
+    }
+
+    /// Returns the address of the peer this event belongs to.
+    pub fn address(&self) -> Address {
+        match self {
+            OwnedEvent::Connect { address, .. }
+            | OwnedEvent::Disconnect { address, .. }
+            | OwnedEvent::Receive { address, .. } => *address,
+        }
+    }
+}
+
 impl<'a, T> Event<'a, T> {
-    pub(crate) fn from_sys_event<'b>(event_sys: &'b ENetEvent) -> Option<Event<'a, T>> {
+    /// Converts this event into an `OwnedEvent`, releasing the borrow of the `Host`,
+    /// so it can be stored, collected with other events, or sent to another thread.
+    ///
+    /// Like dropping the event, this frees the data associated with a disconnected peer.
+    pub fn into_owned(self) -> OwnedEvent {
+        // `Event` implements `Drop`, so its packet can only be moved out without running it
+        let mut event = ManuallyDrop::new(self);
+
+        match &mut *event {
+            Event::Connect(peer) => OwnedEvent::Connect {
+                peer: peer.id(),
+                address: peer.address(),
+                data: peer.event_data(),
+            },
+            Event::Disconnect(peer, data, kind) => {
+                peer.set_data(None);
+
+                OwnedEvent::Disconnect {
+                    peer: peer.id(),
+                    address: peer.address(),
+                    data: *data,
+                    kind: *kind,
+                }
+            }
+            Event::Receive {
+                sender,
+                channel_id,
+                packet,
+            } => OwnedEvent::Receive {
+                peer: sender.id(),
+                address: sender.address(),
+                channel_id: *channel_id,
+                packet: unsafe { ptr::read(packet) },
+            },
+        }
+    }
+
+    /// `connect_id` is the connect ID of the connection the event belongs to, see `Peer::with_connect_id`,
+    /// and `disconnect_kind` has to be set for `Disconnect` events.
+    pub(crate) fn from_sys_event<'b>(
+        event_sys: &'b ENetEvent,
+        connect_id: u32,
+        disconnect_kind: Option<DisconnectKind>,
+        layers: *mut Layers,
+    ) -> Option<Event<'a, T>> {
         #[allow(non_upper_case_globals)]
         match event_sys.type_ {
             _ENetEventType_ENET_EVENT_TYPE_NONE => None,
             _ENetEventType_ENET_EVENT_TYPE_CONNECT => {
-                Some(Event::Connect(Peer::new(event_sys.peer)))
+                Some(Event::Connect(Peer::with_connect_id(event_sys.peer, connect_id, layers)))
             }
             _ENetEventType_ENET_EVENT_TYPE_DISCONNECT => Some(Event::Disconnect(
-                Peer::new(event_sys.peer),
+                Peer::with_connect_id(event_sys.peer, connect_id, layers),
                 event_sys.data,
+                disconnect_kind.expect("missing kind for Disconnect event"),
             )),
-            _ENetEventType_ENET_EVENT_TYPE_RECEIVE => Some(Event::Receive {
-                sender: Peer::new(event_sys.peer),
-                channel_id: event_sys.channelID,
-                packet: Packet::from_sys_packet(event_sys.packet),
-            }),
+            _ENetEventType_ENET_EVENT_TYPE_RECEIVE => {
+                let sender = Peer::with_connect_id(event_sys.peer, connect_id, layers);
+                let packet = Packet::received(event_sys.packet, sender.id(), event_sys.channelID);
+
+                Some(Event::Receive {
+                    sender,
+                    channel_id: event_sys.channelID,
+                    packet,
+                })
+            }
             _ => panic!("unrecognized event type: {}", event_sys.type_),
         }
     }
+
+    /// Returns the `PeerId` of the peer this event belongs to.
+    pub fn peer_id(&self) -> PeerId {
+        match self {
+            Event::Connect(peer) => peer.id(),
+            Event::Disconnect(peer, ..) => peer.id(),
+            Event::Receive { sender, .. } => sender.id(),
+        }
+    }
+}
+
+impl<'a, T> From<Event<'a, T>> for OwnedEvent {
+    fn from(event: Event<'a, T>) -> OwnedEvent {
+        event.into_owned()
+    }
 }
 
 impl<'a, T> Drop for Event<'a, T> {
@@ -57,8 +205,57 @@ impl<'a, T> Drop for Event<'a, T> {
             // However, this is *not really clear* in the ENet docs!
             // It looks like the Peer *might* live longer, but not shorter, so it should be safe
             // to destroy the associated data (if any) here.
-            Event::Disconnect(peer, _) => peer.set_data(None),
+            Event::Disconnect(peer, ..) => peer.set_data(None),
             _ => (),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use super::OwnedEvent;
+    use crate::{
+        tests::{localhost, service_until, ENET},
+        Packet, PacketMode,
+    };
+
+    #[test]
+    fn test_owned_events_outlive_service() {
+        let address = localhost(12373);
+        let mut server = ENET.host_builder().address(address).build::<()>().unwrap();
+        let mut client = ENET.host_builder().build::<()>().unwrap();
+
+        let peer = client.connect(&address, 1, 0).unwrap();
+        let mut packet_sent = false;
+        let mut events = Vec::new();
+
+        service_until(Duration::from_secs(1), || {
+            if let Some(OwnedEvent::Connect { peer: connected, .. }) = client.service(0).unwrap().map(OwnedEvent::from) {
+                if connected == peer && !packet_sent {
+                    let packet = Packet::new(b"owned", PacketMode::ReliableSequenced).unwrap();
+                    client.peer_mut(peer).unwrap().send_packet(packet, 0).unwrap();
+                    packet_sent = true;
+                }
+            }
+
+            while let Some(event) = server.service(5).unwrap() {
+                events.push(event.into_owned());
+            }
+            Some(()).filter(|_| events.len() >= 2)
+        })
+        .expect("receiving timed out");
+
+        // the events no longer borrow the host, so they can be processed anywhere
+        let events = thread::spawn(move || events).join().unwrap();
+
+        match &events[..] {
+            [OwnedEvent::Connect { peer: connected, .. }, OwnedEvent::Receive { peer: sender, channel_id: 0, packet, .. }] => {
+                assert_eq!(connected, sender);
+                assert_eq!(packet.data(), b"owned");
+            }
+            other => panic!("unexpected events: {:?}", other),
+        }
+    }
+}
@@ -1,16 +1,40 @@
-use std::{marker::PhantomData, collections::HashMap, sync::Mutex, process, panic, mem::{self, ManuallyDrop}, slice};
-use lazy_static::lazy_static;
+use std::{marker::PhantomData, mem, io, net::UdpSocket, os::raw::c_int, path::Path, time::{Duration, Instant}};
+#[cfg(unix)]
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+#[cfg(windows)]
+use std::os::windows::io::{FromRawSocket, IntoRawSocket};
 use std::mem::MaybeUninit;
-use std::sync::Arc;
-use log::error;
+use std::sync::{Arc, Mutex};
 
-use crate::{Address, EnetKeepAlive, Error, Event, Peer, socket::Socket};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "auth")]
+use crate::{auth::Authenticator, Authentication};
+#[cfg(feature = "encryption")]
+use crate::{encryption::Encryptor, Encryption};
+use crate::{
+    capture::Capture,
+    compress::{self, CompressionCounters},
+    conditioner::{Conditioner, SharedConditioner},
+    intercept::{self, Intercept},
+    oob,
+    socket::{Socket, SocketHooks},
+    Address, Checksum, Compression, CompressionError, CompressionStats, DisconnectKind, EnetKeepAlive, Error, Event,
+    HostCreationError, InterceptContext, InterceptPanicPolicy, InterceptVerdict, NetworkConditioner, OwnedEvent, Packet,
+    Peer, PeerId, PeerRef, PeerState,
+};
 
 use citizen_enet_sys::{
-    enet_host_bandwidth_limit, enet_host_channel_limit, enet_host_check_events, enet_host_connect,
-    enet_host_destroy, enet_host_flush, enet_host_service, ENetHost, ENetPeer,
-    ENET_PROTOCOL_MAXIMUM_CHANNEL_COUNT, ENetEvent,
+    enet_host_bandwidth_limit, enet_host_channel_limit, enet_host_check_events, enet_host_compress,
+    enet_host_broadcast, enet_host_connect, enet_host_create, enet_host_destroy, enet_host_flush, enet_host_service,
+    enet_packet_destroy, enet_socket_destroy, ENetHost, ENetPeer, ENET_SOCKET_NULL,
+    ENET_PROTOCOL_MAXIMUM_CHANNEL_COUNT, ENET_PROTOCOL_MAXIMUM_MTU, ENET_PROTOCOL_MAXIMUM_PEER_ID,
+    ENET_PROTOCOL_MINIMUM_MTU, ENetEvent, ENetSocket,
+    _ENetEventType_ENET_EVENT_TYPE_CONNECT, _ENetEventType_ENET_EVENT_TYPE_DISCONNECT,
+    _ENetPeerState_ENET_PEER_STATE_CONNECTED, _ENetPeerState_ENET_PEER_STATE_DISCONNECTED,
 };
+use citizen_enet_sys::{enet_peer_send, ENetPacket};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 /// Represents a bandwidth limit or unlimited.
@@ -57,8 +81,340 @@ impl BandwidthLimit {
     }
 }
 
-lazy_static! {
-    static ref HOST_INTERCEPT_HANDLERS: Mutex<HashMap<usize, usize>> = Mutex::new(HashMap::new());
+/// Traffic counters of a `Host`, as returned by `Host::traffic`.
+///
+/// ENet's own counters are 32 bit and wrap around; `Host` moves them into these 64 bit counters
+/// whenever it is serviced or flushed, so they never wrap in practice.
+/// Serializable with the `serde` feature.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct HostTraffic {
+    /// Total number of bytes sent.
+    pub sent_bytes: u64,
+    /// Total number of datagrams sent.
+    pub sent_packets: u64,
+    /// Total number of bytes received.
+    pub received_bytes: u64,
+    /// Total number of datagrams received.
+    pub received_packets: u64,
+}
+
+/// Builder for a `Host`, giving access to all settings of the underlying ENet host. Created through `Enet::host_builder`.
+///
+/// All settings are validated against the limits of ENet's protocol when calling `build`, before the host is created.
+#[derive(Debug)]
+pub struct HostBuilder {
+    keep_alive: Arc<EnetKeepAlive>,
+
+    address: Option<Address>,
+    socket: Option<UdpSocket>,
+    peer_limit: usize,
+    channel_limit: ChannelLimit,
+    incoming_bandwidth: BandwidthLimit,
+    outgoing_bandwidth: BandwidthLimit,
+    mtu: Option<u32>,
+    duplicate_peers: Option<usize>,
+    maximum_packet_size: Option<usize>,
+    maximum_waiting_data: Option<usize>,
+    checksum: Checksum,
+    compression: Compression,
+}
+
+impl HostBuilder {
+    pub(in crate) fn new(keep_alive: Arc<EnetKeepAlive>) -> HostBuilder {
+        HostBuilder {
+            keep_alive,
+            address: None,
+            socket: None,
+            peer_limit: 1,
+            channel_limit: ChannelLimit::Maximum,
+            incoming_bandwidth: BandwidthLimit::Unlimited,
+            outgoing_bandwidth: BandwidthLimit::Unlimited,
+            mtu: None,
+            duplicate_peers: None,
+            maximum_packet_size: None,
+            maximum_waiting_data: None,
+            checksum: Checksum::None,
+            compression: Compression::None,
+        }
+    }
+
+    /// Sets the address to listen on. Client-only hosts don't need to set an address.
+    pub fn address(mut self, address: Address) -> Self {
+        self.address = Some(address);
+        self
+    }
+
+    /// Sets an already bound socket for the host to adopt, instead of creating and binding its own.
+    /// Takes precedence over `address`.
+    ///
+    /// ENet's sockets are dual-stack IPv6 sockets, so `socket` has to be an IPv6 socket.
+    /// It is made non-blocking, but its other options are left as they are.
+    pub fn socket(mut self, socket: UdpSocket) -> Self {
+        self.socket = Some(socket);
+        self
+    }
+
+    /// Sets the maximum number of peers the host can be connected to at once. Defaults to 1.
+    pub fn peer_limit(mut self, peer_limit: usize) -> Self {
+        self.peer_limit = peer_limit;
+        self
+    }
+
+    /// Sets the maximum number of channels of connections to this host. Defaults to `ChannelLimit::Maximum`.
+    pub fn channel_limit(mut self, channel_limit: ChannelLimit) -> Self {
+        self.channel_limit = channel_limit;
+        self
+    }
+
+    /// Sets the downstream bandwidth of the host. Defaults to `BandwidthLimit::Unlimited`.
+    pub fn incoming_bandwidth(mut self, incoming_bandwidth: BandwidthLimit) -> Self {
+        self.incoming_bandwidth = incoming_bandwidth;
+        self
+    }
+
+    /// Sets the upstream bandwidth of the host. Defaults to `BandwidthLimit::Unlimited`.
+    pub fn outgoing_bandwidth(mut self, outgoing_bandwidth: BandwidthLimit) -> Self {
+        self.outgoing_bandwidth = outgoing_bandwidth;
+        self
+    }
+
+    /// Sets the MTU used for new connections of the host, in bytes. Defaults to ENet's default MTU.
+    pub fn mtu(mut self, mtu: u32) -> Self {
+        self.mtu = Some(mtu);
+        self
+    }
+
+    /// Sets the maximum number of peers that may connect from the same IP address.
+    /// Defaults to ENet's default (4095, the maximum peer ID), which does not limit peers from the same address.
+    pub fn duplicate_peers(mut self, duplicate_peers: usize) -> Self {
+        self.duplicate_peers = Some(duplicate_peers);
+        self
+    }
+
+    /// Sets the maximum size of a packet that may be received, in bytes. Defaults to ENet's default (32 MiB).
+    pub fn maximum_packet_size(mut self, maximum_packet_size: usize) -> Self {
+        self.maximum_packet_size = Some(maximum_packet_size);
+        self
+    }
+
+    /// Sets the maximum amount of received data, in bytes, that may be buffered for each peer
+    /// before it is disconnected. Defaults to ENet's default (32 MiB).
+    pub fn maximum_waiting_data(mut self, maximum_waiting_data: usize) -> Self {
+        self.maximum_waiting_data = Some(maximum_waiting_data);
+        self
+    }
+
+    /// Sets the checksum used for datagrams of the host. Defaults to `Checksum::None`.
+    pub fn checksum(mut self, checksum: Checksum) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    /// Sets the compression used for datagrams of the host. Defaults to `Compression::None`.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    fn validate(&self) -> Result<(), HostCreationError> {
+        const MAX_PEERS: usize = ENET_PROTOCOL_MAXIMUM_PEER_ID as usize;
+
+        check_setting("peer_limit", self.peer_limit, 1, MAX_PEERS)?;
+
+        if let ChannelLimit::Limited(channel_limit) = self.channel_limit {
+            check_setting("channel_limit", channel_limit, 1, ENET_PROTOCOL_MAXIMUM_CHANNEL_COUNT as usize)?;
+        }
+
+        if let Some(mtu) = self.mtu {
+            check_setting(
+                "mtu",
+                mtu as usize,
+                ENET_PROTOCOL_MINIMUM_MTU as usize,
+                ENET_PROTOCOL_MAXIMUM_MTU as usize,
+            )?;
+        }
+
+        if let Some(duplicate_peers) = self.duplicate_peers {
+            check_setting("duplicate_peers", duplicate_peers, 1, MAX_PEERS)?;
+        }
+
+        if let Some(maximum_packet_size) = self.maximum_packet_size {
+            check_setting("maximum_packet_size", maximum_packet_size, 1, usize::MAX)?;
+        }
+
+        if let Some(maximum_waiting_data) = self.maximum_waiting_data {
+            check_setting("maximum_waiting_data", maximum_waiting_data, 1, usize::MAX)?;
+        }
+
+        Ok(())
+    }
+
+    /// Validates the settings and creates the `Host`.
+    ///
+    /// The type `T` specifies the data associated with corresponding `Peer`s.
+    pub fn build<T>(self) -> Result<Host<T>, HostCreationError> {
+        self.validate()?;
+
+        let socket_addr = match &self.socket {
+            Some(socket) => Some(adoptable_socket_addr(socket).map_err(HostCreationError::InvalidSocket)?),
+            None => None,
+        };
+
+        // an adopted socket replaces the one created by ENet, which therefore does not need to be bound
+        let address = if self.socket.is_some() { None } else { self.address };
+        let addr = address.as_ref().map(Address::enet_address);
+        let inner = unsafe {
+            enet_host_create(
+                addr.as_ref()
+                    .map(|p| p as *const _)
+                    .unwrap_or(std::ptr::null()),
+                self.peer_limit,
+                self.channel_limit.to_enet_usize(),
+                self.incoming_bandwidth.to_enet_u32(),
+                self.outgoing_bandwidth.to_enet_u32(),
+            )
+        };
+
+        if inner.is_null() {
+            // `enet_host_create` only fails on allocation or socket errors, which leave errno set.
+            let error = io::Error::last_os_error();
+
+            return Err(match address {
+                Some(address) => HostCreationError::BindFailed { address, error },
+                None => HostCreationError::CreateFailed(error),
+            });
+        }
+
+        let mut host = Host::new(self.keep_alive, inner);
+
+        if let (Some(socket), Some(socket_addr)) = (self.socket, socket_addr) {
+            #[cfg(unix)]
+            let socket = socket.into_raw_fd();
+            #[cfg(windows)]
+            let socket = socket.into_raw_socket() as _;
+
+            unsafe {
+                enet_socket_destroy((*inner).socket);
+                (*inner).socket = socket;
+                (*inner).address = socket_addr.enet_address();
+            }
+
+            host.socket().set_nonblocking(true).map_err(HostCreationError::InvalidSocket)?;
+        }
+
+        host.set_checksum(self.checksum);
+
+        unsafe {
+            if let Some(mtu) = self.mtu {
+                (*inner).mtu = mtu;
+            }
+            if let Some(duplicate_peers) = self.duplicate_peers {
+                (*inner).duplicatePeers = duplicate_peers;
+            }
+            if let Some(maximum_packet_size) = self.maximum_packet_size {
+                (*inner).maximumPacketSize = maximum_packet_size;
+            }
+            if let Some(maximum_waiting_data) = self.maximum_waiting_data {
+                (*inner).maximumWaitingData = maximum_waiting_data;
+            }
+        }
+
+        host.set_compressor(self.compression)
+            .map_err(HostCreationError::CompressionFailed)?;
+
+        Ok(host)
+    }
+}
+
+/// Returns the address `socket` is bound to, if it can be adopted by ENet.
+fn adoptable_socket_addr(socket: &UdpSocket) -> io::Result<Address> {
+    let address = socket.local_addr()?;
+
+    if !address.is_ipv6() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "ENet requires an IPv6 socket"));
+    }
+
+    Ok(Address(address))
+}
+
+fn check_setting(setting: &'static str, value: usize, min: usize, max: usize) -> Result<(), HostCreationError> {
+    if value < min || value > max {
+        return Err(HostCreationError::InvalidSetting { setting, value, min, max });
+    }
+
+    Ok(())
+}
+
+/// The state of a peer slot before the current service, see `Host::classify_disconnect`.
+#[derive(Debug, Copy, Clone)]
+struct PeerSnapshot {
+    state: PeerState,
+    // `enet_peer_reset` clears both, so they are gone by the time the `Disconnect` event is returned
+    earliest_timeout: u32,
+    timeout_minimum: u32,
+}
+
+impl Default for PeerSnapshot {
+    fn default() -> PeerSnapshot {
+        PeerSnapshot {
+            state: PeerState::Disconnected,
+            earliest_timeout: 0,
+            timeout_minimum: 0,
+        }
+    }
+}
+
+/// The authentication and encryption of a `Host`, boxed so its `Peer`s can refer to it while the `Host` is moved.
+#[derive(Default)]
+pub(crate) struct Layers {
+    #[cfg(feature = "auth")]
+    authenticator: Option<Authenticator>,
+    #[cfg(feature = "encryption")]
+    encryptor: Option<Encryptor>,
+}
+
+impl Layers {
+    /// Returns whether `peer` did not authenticate yet, so the application must not send anything to it.
+    #[cfg(feature = "auth")]
+    pub(crate) unsafe fn is_pending(&self, peer: *mut ENetPeer) -> bool {
+        self.authenticator
+            .as_ref()
+            .is_some_and(|authenticator| authenticator.is_pending(peer))
+    }
+
+    /// Queues `packet` to be sent to `peer` like `enet_peer_send`, encrypting it if the host has an `Encryption`,
+    /// see `Encryptor::send`.
+    pub(crate) unsafe fn send(&mut self, peer: *mut ENetPeer, channel_id: u8, packet: *mut ENetPacket) -> c_int {
+        #[cfg(feature = "encryption")]
+        if let Some(encryptor) = &mut self.encryptor {
+            return encryptor.send(peer, channel_id, packet);
+        }
+
+        enet_peer_send(peer, channel_id, packet)
+    }
+
+    /// Decrypts `packet`, received from `peer` through `enet_peer_receive`, if the host has an `Encryption`,
+    /// see `Encryptor::receive`.
+    #[cfg(feature = "encryption")]
+    pub(crate) unsafe fn receive(
+        &mut self,
+        peer: *mut ENetPeer,
+        channel_id: u8,
+        packet: *mut ENetPacket,
+    ) -> *mut ENetPacket {
+        match &mut self.encryptor {
+            Some(encryptor) => encryptor.receive(peer, channel_id, packet),
+            None => packet,
+        }
+    }
+}
+
+/// An event returned by ENet, with the connect ID and `DisconnectKind` tracked for it by the `Host`.
+struct RawEvent {
+    sys_event: ENetEvent,
+    connect_id: u32,
+    disconnect_kind: Option<DisconnectKind>,
 }
 
 /// A `Host` represents one endpoint of an ENet connection. Created through `Enet`.
@@ -66,6 +422,16 @@ lazy_static! {
 /// This type provides functionality such as connection establishment and packet transmission.
 pub struct Host<T> {
     inner: *mut ENetHost,
+    // connect IDs of the connections occupying each peer slot, see `Host::track_connect_id`
+    peer_connect_ids: Vec<u32>,
+    compression_counters: Arc<CompressionCounters>,
+    traffic: HostTraffic,
+    intercept: Box<Intercept>,
+    // each peer slot before the current service, see `Host::disconnect_kind`
+    peer_snapshots: Vec<PeerSnapshot>,
+    // kinds of disconnections whose events have not been returned yet, per peer slot
+    pending_disconnect_kinds: Vec<Option<DisconnectKind>>,
+    layers: Box<Layers>,
 
     _keep_alive: Arc<EnetKeepAlive>,
     _peer_data: PhantomData<*const T>,
@@ -77,48 +443,198 @@ impl<T> Host<T> {
     pub(in crate) fn new(_keep_alive: Arc<EnetKeepAlive>, inner: *mut ENetHost) -> Host<T> {
         assert!(!inner.is_null());
 
+        let peer_count = unsafe { (*inner).peerCount };
+
         Host {
             inner,
+            peer_connect_ids: vec![0; peer_count],
+            compression_counters: Arc::new(CompressionCounters::default()),
+            traffic: HostTraffic::default(),
+            intercept: Box::default(),
+            peer_snapshots: vec![PeerSnapshot::default(); peer_count],
+            pending_disconnect_kinds: vec![None; peer_count],
+            layers: Box::default(),
             _keep_alive,
             _peer_data: PhantomData,
         }
     }
 
-    /// Callback the user can set to intercept received raw UDP packets.
-    pub fn set_intercept<F>(&mut self, intercept_fn: F)
-        where F: FnMut(&mut Host<T>, Address, &[u8]) -> bool,
-            F: Send + 'static
+    /// Sets a handler that intercepts every raw datagram received by this `Host`, replacing the previous one.
+    ///
+    /// The handler is called from within `Host::service` before ENet handles the datagram,
+    /// and decides through its `InterceptVerdict` whether ENet gets to handle it.
+    pub fn set_intercept<F>(&mut self, handler: F)
+    where
+        F: FnMut(&mut InterceptContext<'_>) -> InterceptVerdict + Send + 'static,
     {
-        let handler: Box<Box<dyn FnMut(_, _, _) -> _>> = Box::new(Box::new(intercept_fn));
-        if let Some(prev_addr) = HOST_INTERCEPT_HANDLERS.lock().unwrap()
-            .insert(self.inner as usize, Box::into_raw(handler) as usize)
-        {
-            let _: Box<Box<dyn FnMut(&mut Host<T>, Address, &[u8]) -> bool>> = unsafe { Box::from_raw(prev_addr as *mut _) };
-        }
+        self.intercept.handler = Some(Box::new(handler));
+        self.update_intercept_callback();
+    }
+
+    /// Removes the handler set through `Host::set_intercept`.
+    pub fn clear_intercept(&mut self) {
+        self.intercept.handler = None;
+        self.update_intercept_callback();
+    }
+
+    /// Registers a handler for connectionless out-of-band messages with the command `command`,
+    /// replacing the previous handler for it. See `OOB_PREFIX` for the format of these messages.
+    ///
+    /// The handler is called from within `Host::service` with the payload of the message, and can
+    /// answer through `InterceptContext::reply_oob`. Messages it handles are not seen by the intercept handler;
+    /// all other datagrams, including OOB messages without a registered command, are handled as before.
+    pub fn register_oob_handler<F>(&mut self, command: &str, handler: F)
+    where
+        F: FnMut(&mut InterceptContext<'_>, &[u8]) + Send + 'static,
+    {
+        self.intercept.oob_handlers.insert(command.to_owned(), Box::new(handler));
+        self.update_intercept_callback();
+    }
+
+    /// Removes the handler for the out-of-band command `command`, returning whether there was one.
+    pub fn unregister_oob_handler(&mut self, command: &str) -> bool {
+        let removed = self.intercept.oob_handlers.remove(command).is_some();
+        self.update_intercept_callback();
+        removed
+    }
+
+    /// Sends a connectionless out-of-band message to `address`, returning the number of bytes sent.
+    ///
+    /// The command and the payload are separated by a newline, see `OOB_PREFIX`.
+    pub fn send_oob(&mut self, address: &Address, command: &str, payload: &[u8]) -> Result<u32, Error> {
+        self.socket().send_data(address, &oob::encode(command, payload))
+    }
+
+    /// Sets what happens when the intercept handler or an OOB handler panics.
+    pub fn set_intercept_panic_policy(&mut self, policy: InterceptPanicPolicy) {
+        self.intercept.panic_policy = policy;
+    }
+
+    /// Installs ENet's intercept callback only while it is needed, so datagrams are not intercepted for nothing.
+    fn update_intercept_callback(&mut self) {
+        let callback = if self.intercept.is_active() {
+            Some(intercept::callback as _)
+        } else {
+            None
+        };
+
         unsafe {
-            (*self.inner).intercept = Some(Self::intercept_handler)
+            (*self.inner).intercept = callback;
+        }
+    }
+
+    /// Attaches a `NetworkConditioner`, which simulates a bad network for this host, replacing the previous one.
+    ///
+    /// Received datagrams go through the conditioner before the intercept handler and ENet see them, and datagrams
+    /// sent through `Host::socket`, `Host::send_oob` or an `InterceptContext` before they leave the socket.
+    /// ENet writes its own datagrams to the socket directly, so they are conditioned by the host receiving them;
+    /// to condition both directions of an ENet connection, attach a conditioner to both hosts.
+    /// Datagrams held back by the conditioner are delivered while the host is serviced.
+    /// Fails if the loopback socket the conditioner needs to deliver held back datagrams cannot be created.
+    pub fn set_network_conditioner(&mut self, conditioner: NetworkConditioner) -> io::Result<()> {
+        let conditioner = Conditioner::new(conditioner, self.local_addr()?)?;
+        self.intercept.hooks.conditioner = Some(Arc::new(Mutex::new(conditioner)));
+        self.update_intercept_callback();
+        Ok(())
+    }
+
+    /// Removes the `NetworkConditioner` set through `Host::set_network_conditioner`,
+    /// dropping all datagrams it still holds back.
+    pub fn clear_network_conditioner(&mut self) {
+        self.intercept.hooks.conditioner = None;
+        self.update_intercept_callback();
+    }
+
+    /// Starts capturing the datagrams of this host into a new pcap file at `path`, replacing a running capture.
+    ///
+    /// Every datagram received by the host is recorded as it arrives, before the intercept handler, ENet or a
+    /// `NetworkConditioner` see it. Of the datagrams sent by the host, only those sent through `Host::socket`,
+    /// `Host::send_oob` or an `InterceptContext` are recorded, before a `NetworkConditioner` delays or drops them.
+    /// ENet writes its own datagrams to the socket directly, so to see both directions of an ENet connection,
+    /// capture both of its hosts. Capturing does not change how datagrams are sent or received.
+    ///
+    /// Datagrams are recorded as raw IP packets with synthetic IP and UDP headers, using the actual addresses
+    /// and ports, so they can be inspected with Wireshark and its ENet dissector.
+    pub fn start_capture<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let capture = Capture::create(path.as_ref(), self.local_addr()?)?;
+
+        let previous = self.intercept.hooks.capture.replace(Arc::new(Mutex::new(capture)));
+        self.update_intercept_callback();
+
+        if let Some(previous) = previous {
+            Capture::lock(&previous).finish()?;
         }
+
+        Ok(())
     }
 
-    unsafe extern "C" fn intercept_handler(c_host: *mut ENetHost, event: *mut ENetEvent) -> i32 {
-        let result = panic::catch_unwind(|| {
-            let addr = *HOST_INTERCEPT_HANDLERS.lock().unwrap().get(&(c_host as usize)).unwrap();
-            let data = slice::from_raw_parts((*c_host).receivedData, (*c_host).receivedDataLength);
-            let closure: &mut Box<dyn FnMut(&mut Host<T>, Address, &[u8]) -> bool> = unsafe { mem::transmute(addr) };
-            let mut host = ManuallyDrop::new(Self::new(Arc::new(EnetKeepAlive), c_host));
-            closure(&mut host, Address::from_enet_address(&(*c_host).receivedAddress), data)
-        });
+    /// Stops the capture started through `Host::start_capture` and flushes its file,
+    /// returning the error that stopped it early, if any.
+    pub fn stop_capture(&mut self) -> io::Result<()> {
+        let capture = self.intercept.hooks.capture.take();
+        self.update_intercept_callback();
 
-        match result {
-            Ok(r) => r as i32,
-            // TODO: return -1 and log error instead
-            Err(err) => {
-                error!("panic in intercept_handler: {:?}", err);
-                -1
-            },
+        match capture {
+            Some(capture) => Capture::lock(&capture).finish(),
+            None => Ok(()),
         }
     }
 
+    /// Requires the peers of this host to authenticate with the pre-shared key of `authentication`, replacing
+    /// the previous authentication. Requires the `auth` feature.
+    ///
+    /// A newly connected peer is pending until it authenticated: its `Connect` event is withheld from the
+    /// application and its packets are held back, and it is listed by `Host::peers` in the `Connected` state, but
+    /// cannot be sent any packets: `Peer::send_packet` fails with `PeerError::Unauthenticated`, and
+    /// `Host::broadcast` and `Host::multicast` skip it. Once it authenticated, `Host::service` returns its `Connect`
+    /// event, followed by the packets held back. Accepted peers that fail to authenticate in time
+    /// are reset without any event, while connections initiated by `Host::connect` are disconnected with
+    /// `AUTHENTICATION_FAILED`, so their `Disconnect` event is returned. Both hosts of a connection have to
+    /// require the same authentication, which only applies to connections established after setting it.
+    #[cfg(feature = "auth")]
+    pub fn set_authentication(&mut self, authentication: Authentication) {
+        self.layers.authenticator = Some(Authenticator::new(self.inner, authentication));
+    }
+
+    /// Removes the authentication set through `Host::set_authentication`, so pending peers are treated
+    /// as authenticated, but without a `Connect` event. Requires the `auth` feature.
+    #[cfg(feature = "auth")]
+    pub fn clear_authentication(&mut self) {
+        self.layers.authenticator = None;
+    }
+
+    /// Encrypts the packets exchanged with the peers of this host, replacing the previous encryption.
+    /// Requires the `encryption` feature.
+    ///
+    /// A newly connected peer is pending until its handshake completed: its `Connect` event is withheld from the
+    /// application, and its packets are held back until `Host::service` returned the `Connect` event. Afterwards,
+    /// packets are encrypted by `Peer::send_packet`, `Host::broadcast` and `Host::multicast`, and decrypted before
+    /// they are returned in an `Event::Receive` or by `Peer::receive`. Peers whose handshake fails or times out,
+    /// or which send a packet that fails authentication, are disconnected with `ENCRYPTION_FAILED` (accepted peers
+    /// without any event if the application did not get their `Connect` event yet).
+    ///
+    /// Both hosts of a connection have to use encryption with the same reserved channel, which only applies to
+    /// connections established after setting it. When combined with `Host::set_authentication`,
+    /// the handshake starts once the peer authenticated.
+    #[cfg(feature = "encryption")]
+    pub fn set_encryption(&mut self, encryption: Encryption) {
+        self.layers.encryptor = Some(Encryptor::new(self.inner, encryption));
+    }
+
+    /// Removes the encryption set through `Host::set_encryption`, so packets are sent and received in plaintext.
+    /// Requires the `encryption` feature.
+    #[cfg(feature = "encryption")]
+    pub fn clear_encryption(&mut self) {
+        self.layers.encryptor = None;
+    }
+
+    /// Returns the static public key the peer identified by `id` presented in its handshake,
+    /// see `Host::set_encryption`. Requires the `encryption` feature.
+    #[cfg(feature = "encryption")]
+    pub fn peer_public_key(&self, id: PeerId) -> Option<Vec<u8>> {
+        self.layers.encryptor.as_ref()?.peer_public_key(id.index(), id.connect_id())
+    }
+
     /// Sends any queued packets on the host specified to its designated peers.
     ///
     /// This function need only be used in circumstances where one wishes to send queued packets earlier than in a call to `Host::service()`.
@@ -126,6 +642,38 @@ impl<T> Host<T> {
         unsafe {
             enet_host_flush(self.inner);
         }
+
+        self.collect_traffic();
+    }
+
+    /// Returns the traffic counters of this `Host`, accumulated since its creation or the last `reset_traffic`.
+    pub fn traffic(&self) -> HostTraffic {
+        let inner = unsafe { &*self.inner };
+
+        HostTraffic {
+            sent_bytes: self.traffic.sent_bytes + inner.totalSentData as u64,
+            sent_packets: self.traffic.sent_packets + inner.totalSentPackets as u64,
+            received_bytes: self.traffic.received_bytes + inner.totalReceivedData as u64,
+            received_packets: self.traffic.received_packets + inner.totalReceivedPackets as u64,
+        }
+    }
+
+    /// Resets the traffic counters of this `Host` to zero, e.g. to measure traffic per interval.
+    pub fn reset_traffic(&mut self) {
+        self.collect_traffic();
+        self.traffic = HostTraffic::default();
+    }
+
+    /// Moves ENet's 32 bit `total*` counters into `traffic`, before they can wrap around.
+    fn collect_traffic(&mut self) {
+        self.traffic = self.traffic();
+
+        unsafe {
+            (*self.inner).totalSentData = 0;
+            (*self.inner).totalSentPackets = 0;
+            (*self.inner).totalReceivedData = 0;
+            (*self.inner).totalReceivedPackets = 0;
+        }
     }
 
     /// Sets the bandwith limits for this `Host`.
@@ -143,6 +691,36 @@ impl<T> Host<T> {
         }
     }
 
+    /// Sets the checksum attached to and verified on every datagram of this `Host`.
+    ///
+    /// Peers have to use the same checksum, as datagrams failing verification are dropped.
+    pub fn set_checksum(&mut self, checksum: Checksum) {
+        unsafe {
+            (*self.inner).checksum = checksum.to_sys_callback();
+        }
+    }
+
+    /// Sets the compression used for datagrams sent by this `Host`, replacing the previous one.
+    ///
+    /// Peers have to use the same compression for them to be able to decompress the datagrams.
+    pub fn set_compressor(&mut self, compression: Compression) -> Result<(), CompressionError> {
+        let compressor = compress::to_sys_compressor(compression, self.compression_counters.clone())?;
+
+        unsafe {
+            enet_host_compress(
+                self.inner,
+                compressor.as_ref().map(|c| c as *const _).unwrap_or(std::ptr::null()),
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Returns the compression counters of this `Host`, accumulated over all compressors set so far.
+    pub fn compression_stats(&self) -> CompressionStats {
+        self.compression_counters.snapshot()
+    }
+
     /// Sets the maximum allowed channels of future connections.
     pub fn set_channel_limit(&mut self, max_channel_count: ChannelLimit) {
         unsafe {
@@ -165,11 +743,43 @@ impl<T> Host<T> {
         unsafe { (*self.inner).outgoingBandwidth }
     }
 
-    /// Returns the internet address of this `Host`.
+    /// Returns the internet address this `Host` was created with.
+    ///
+    /// For a `Host` bound to port 0, this does not contain the port actually assigned, see `Host::local_addr`.
     pub fn address(&self) -> Address {
         Address::from_enet_address(&unsafe { (*self.inner).address })
     }
 
+    /// Returns the address the socket of this `Host` is actually bound to.
+    pub fn local_addr(&self) -> io::Result<Address> {
+        Socket::<T>::new(self.raw_socket(), SocketHooks::default()).local_addr()
+    }
+
+    /// Returns the time of the last service of this `Host`, in milliseconds of ENet's clock.
+    pub fn service_time(&self) -> u32 {
+        unsafe { (*self.inner).serviceTime }
+    }
+
+    /// Returns the MTU used for new connections of this `Host`, in bytes.
+    pub fn mtu(&self) -> u32 {
+        unsafe { (*self.inner).mtu }
+    }
+
+    /// Returns the maximum number of peers that may connect to this `Host` from the same IP address.
+    pub fn duplicate_peers(&self) -> usize {
+        unsafe { (*self.inner).duplicatePeers }
+    }
+
+    /// Returns the maximum size of a packet that may be received by this `Host`, in bytes.
+    pub fn maximum_packet_size(&self) -> usize {
+        unsafe { (*self.inner).maximumPacketSize }
+    }
+
+    /// Returns the maximum amount of received data, in bytes, that may be buffered for each peer of this `Host`.
+    pub fn maximum_waiting_data(&self) -> usize {
+        unsafe { (*self.inner).maximumWaitingData }
+    }
+
     /// Returns the number of peers allocated for this `Host`.
     pub fn peer_count(&self) -> usize {
         unsafe { (*self.inner).peerCount }
@@ -180,46 +790,436 @@ impl<T> Host<T> {
         let raw_peers =
             unsafe { std::slice::from_raw_parts_mut((*self.inner).peers, (*self.inner).peerCount) };
 
-        raw_peers.iter_mut().map(|rp| Peer::new(rp))
+        let layers: *mut Layers = &mut *self.layers;
+        raw_peers.iter_mut().map(move |rp| Peer::new(rp, layers))
+    }
+
+    /// Queues `packet` to be sent to all connected peers on channel `channel_id`.
+    ///
+    /// The packet is shared by all peers instead of being copied for each of them.
+    pub fn broadcast(&mut self, packet: Packet, channel_id: u8) {
+        // pending peers must not get the packet, and every encrypted peer needs its own ciphertext
+        #[cfg(any(feature = "auth", feature = "encryption"))]
+        if self.filters_peers() {
+            self.multicast(packet, channel_id, |_| true);
+            return;
+        }
+
+        unsafe {
+            enet_host_broadcast(self.inner, channel_id, packet.into_inner());
+        }
+    }
+
+    /// Queues `packet` to be sent on channel `channel_id` to all connected peers for which `filter` returns true.
+    ///
+    /// The packet is shared by all peers instead of being copied for each of them.
+    /// Peers that did not authenticate yet (see `Host::set_authentication`) are skipped.
+    /// Returns the number of peers the packet was queued for.
+    pub fn multicast(&mut self, packet: Packet, channel_id: u8, filter: impl Fn(&Peer<'_, T>) -> bool) -> usize {
+        let packet = packet.into_inner();
+        let mut recipients = 0;
+        let layers: *mut Layers = &mut *self.layers;
+
+        for index in 0..self.peer_count() {
+            let raw_peer = unsafe { (*self.inner).peers.add(index) };
+
+            if unsafe { (*raw_peer).state } != _ENetPeerState_ENET_PEER_STATE_CONNECTED
+                || !filter(&Peer::new(raw_peer, layers))
+            {
+                continue;
+            }
+            #[cfg(feature = "auth")]
+            if unsafe { self.layers.is_pending(raw_peer) } {
+                continue;
+            }
+
+            let res = unsafe { self.layers.send(raw_peer, channel_id, packet) };
+
+            // like `enet_host_broadcast`, failing peers (e.g. with too few channels) are skipped
+            if res == 0 {
+                recipients += 1;
+            }
+        }
+
+        // every peer the packet was queued for holds a reference, and the last one destroys it
+        unsafe {
+            if (*packet).referenceCount == 0 {
+                enet_packet_destroy(packet);
+            }
+        }
+
+        recipients
+    }
+
+    /// Returns whether an `Authentication` or `Encryption` is set, so ENet cannot send packets to all peers by itself.
+    #[cfg(any(feature = "auth", feature = "encryption"))]
+    fn filters_peers(&self) -> bool {
+        #[cfg(feature = "auth")]
+        if self.layers.authenticator.is_some() {
+            return true;
+        }
+        #[cfg(feature = "encryption")]
+        if self.layers.encryptor.is_some() {
+            return true;
+        }
+
+        false
+    }
+
+    /// Returns a read-only view of the `Peer` identified by `id`, or `None` if its connection has ended and the slot was reset or reused.
+    pub fn peer(&self, id: PeerId) -> Option<PeerRef<'_, T>> {
+        let layers: *const Layers = &*self.layers;
+        self.raw_peer(id).map(|raw_peer| PeerRef::new(raw_peer, layers as *mut _))
+    }
+
+    /// Returns the `Peer` identified by `id` for modification, or `None` if its connection has ended and the slot was reset or reused.
+    pub fn peer_mut(&mut self, id: PeerId) -> Option<Peer<'_, T>> {
+        let layers: *mut Layers = &mut *self.layers;
+        self.raw_peer(id).map(|raw_peer| Peer::new(raw_peer, layers))
+    }
+
+    fn raw_peer(&self, id: PeerId) -> Option<*mut ENetPeer> {
+        if id.index() >= self.peer_count() || id.connect_id() == 0 {
+            return None;
+        }
+
+        let raw_peer = unsafe { (*self.inner).peers.add(id.index()) };
+
+        unsafe {
+            if (*raw_peer).connectID != id.connect_id()
+                || (*raw_peer).state == _ENetPeerState_ENET_PEER_STATE_DISCONNECTED
+            {
+                return None;
+            }
+        }
+
+        Some(raw_peer)
+    }
+
+    /// Keeps `peer_connect_ids` up to date and returns the connect ID of the connection `sys_event` belongs to.
+    ///
+    /// ENet resets a peer before returning its `Disconnect` event, which also clears its connect ID,
+    /// so the connect ID for that event has to be taken from the one cached on connection.
+    fn track_connect_id(&mut self, sys_event: &ENetEvent) -> u32 {
+        if sys_event.peer.is_null() {
+            return 0;
+        }
+
+        let index = unsafe { (*sys_event.peer).incomingPeerID } as usize;
+
+        #[allow(non_upper_case_globals)]
+        match sys_event.type_ {
+            _ENetEventType_ENET_EVENT_TYPE_CONNECT => {
+                // in case the slot's previous connection was reset while being a zombie
+                self.pending_disconnect_kinds[index] = None;
+                self.peer_connect_ids[index] = unsafe { (*sys_event.peer).connectID };
+                self.peer_connect_ids[index]
+            }
+            _ENetEventType_ENET_EVENT_TYPE_DISCONNECT => mem::replace(&mut self.peer_connect_ids[index], 0),
+            _ => unsafe { (*sys_event.peer).connectID },
+        }
+    }
+
+    /// Takes a snapshot of every peer slot before servicing this `Host`.
+    ///
+    /// Peers that became zombies during the last service are classified from their previous snapshot first,
+    /// as their `Disconnect` events may only be returned by this or a later service.
+    fn snapshot_peers(&mut self) {
+        for index in 0..self.peer_snapshots.len() {
+            let peer = unsafe { &*(*self.inner).peers.add(index) };
+            let state = PeerState::from_sys_state(peer.state);
+
+            if state == PeerState::Zombie && self.peer_snapshots[index].state != PeerState::Zombie {
+                self.pending_disconnect_kinds[index] = Some(self.classify_disconnect(index));
+            }
+
+            self.peer_snapshots[index] = PeerSnapshot {
+                state,
+                earliest_timeout: peer.earliestTimeout,
+                timeout_minimum: peer.timeoutMinimum,
+            };
+        }
+    }
+
+    /// Classifies a disconnection from the snapshot of the peer before the service it happened in.
+    ///
+    /// ENet does not report why a peer disconnected, and resets it before returning the `Disconnect` event.
+    /// It only times a peer out once its oldest unacknowledged reliable command (`earliestTimeout`) is overdue
+    /// for at least `timeoutMinimum`, so a disconnection of a peer in that situation is taken as a timeout.
+    fn classify_disconnect(&self, index: usize) -> DisconnectKind {
+        let snapshot = self.peer_snapshots[index];
+        let service_time = unsafe { (*self.inner).serviceTime };
+        let timed_out = snapshot.earliest_timeout != 0
+            && service_time.wrapping_sub(snapshot.earliest_timeout) >= snapshot.timeout_minimum;
+
+        match snapshot.state {
+            PeerState::DisconnectLater | PeerState::Disconnecting => DisconnectKind::LocalRequest,
+            _ if timed_out => DisconnectKind::Timeout,
+            PeerState::Connecting
+            | PeerState::AcknowledgingConnect
+            | PeerState::ConnectionPending
+            | PeerState::ConnectionSucceeded => DisconnectKind::Reset,
+            _ => DisconnectKind::Graceful,
+        }
+    }
+
+    /// Returns the `DisconnectKind` for `sys_event`, if it is a `Disconnect` event.
+    fn disconnect_kind(&mut self, sys_event: &ENetEvent) -> Option<DisconnectKind> {
+        if sys_event.type_ != _ENetEventType_ENET_EVENT_TYPE_DISCONNECT {
+            return None;
+        }
+
+        let index = unsafe { (*sys_event.peer).incomingPeerID } as usize;
+
+        Some(
+            self.pending_disconnect_kinds[index]
+                .take()
+                .unwrap_or_else(|| self.classify_disconnect(index)),
+        )
     }
 
     /// Maintains this host and delivers an event if available.
     ///
     /// This should be called regularly for ENet to work properly with good performance.
     pub fn service(&'_ mut self, timeout_ms: u32) -> Result<Option<Event<'_, T>>, Error> {
+        #[cfg(feature = "encryption")]
+        if let Some(event) = self.released_event() {
+            return Ok(self.deliver(event));
+        }
+
+        let event = self.service_raw(timeout_ms)?;
+        #[cfg(any(feature = "auth", feature = "encryption"))]
+        let event = self.filter_events(event, timeout_ms, Host::service_raw)?;
+
+        Ok(event.and_then(|event| self.deliver(event)))
+    }
+
+    fn service_raw(&mut self, timeout_ms: u32) -> Result<Option<RawEvent>, Error> {
+        #[cfg(feature = "auth")]
+        if let Some(event) = self.authenticated_event() {
+            return Ok(Some(event));
+        }
+
         // ENetEvent is Copy (aka has no Drop impl), so we don't have to make sure we `mem::forget` it later on
         let mut sys_event = MaybeUninit::uninit();
 
-        let res = unsafe { enet_host_service(self.inner, sys_event.as_mut_ptr(), timeout_ms) };
+        self.snapshot_peers();
+
+        let inner = self.inner;
+        let res = match self.intercept.hooks.conditioner.clone() {
+            Some(conditioner) => self.service_conditioned(&conditioner, sys_event.as_mut_ptr(), timeout_ms),
+            None => self
+                .intercept
+                .scope(inner, || unsafe { enet_host_service(inner, sys_event.as_mut_ptr(), timeout_ms) }),
+        };
+
+        self.collect_traffic();
 
+        self.raw_event(res, sys_event)
+    }
+
+    /// Turns the result of servicing this `Host` into a `RawEvent`, keeping track of its peers.
+    fn raw_event(&mut self, res: c_int, sys_event: MaybeUninit<ENetEvent>) -> Result<Option<RawEvent>, Error> {
         match res {
-            r if r > 0 => Ok(Event::from_sys_event(unsafe { &sys_event.assume_init() })),
+            r if r > 0 => {
+                let sys_event = unsafe { sys_event.assume_init() };
+                let connect_id = self.track_connect_id(&sys_event);
+                let disconnect_kind = self.disconnect_kind(&sys_event);
+                Ok(Some(RawEvent {
+                    sys_event,
+                    connect_id,
+                    disconnect_kind,
+                }))
+            }
             0 => Ok(None),
             r if r < 0 => Err(Error(r)),
             _ => panic!("unreachable"),
         }
+    }
+
+    fn deliver(&mut self, event: RawEvent) -> Option<Event<'_, T>> {
+        Event::from_sys_event(&event.sys_event, event.connect_id, event.disconnect_kind, &mut *self.layers)
+    }
+
+    /// Passes `event` through the `Authenticator` and `Encryptor`, getting further events through `next` until one
+    /// is delivered or `timeout_ms` has passed, as the events of peers that are not authenticated or whose handshake
+    /// did not complete are withheld from the application.
+    #[cfg(any(feature = "auth", feature = "encryption"))]
+    fn filter_events(
+        &mut self,
+        mut event: Option<RawEvent>,
+        timeout_ms: u32,
+        mut next: impl FnMut(&mut Self, u32) -> Result<Option<RawEvent>, Error>,
+    ) -> Result<Option<RawEvent>, Error> {
+        let deadline = Instant::now() + Duration::from_millis(timeout_ms.into());
+
+        loop {
+            let now = Instant::now();
+            #[cfg(feature = "auth")]
+            if let Some(authenticator) = &mut self.layers.authenticator {
+                unsafe { authenticator.expire(self.inner, now) };
+            }
+            #[cfg(feature = "encryption")]
+            if let Some(encryptor) = &mut self.layers.encryptor {
+                unsafe { encryptor.expire(self.inner, now) };
+            }
+
+            let mut raw_event = match event {
+                Some(raw_event) => raw_event,
+                None => return Ok(None),
+            };
+            if self.filter_event(&mut raw_event) {
+                return Ok(Some(raw_event));
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            event = next(self, remaining.as_millis() as u32)?;
+        }
+    }
+
+    /// Returns whether `raw_event` is delivered to the application, see `Host::filter_events`.
+    #[cfg(any(feature = "auth", feature = "encryption"))]
+    fn filter_event(&mut self, raw_event: &mut RawEvent) -> bool {
+        #[cfg(feature = "auth")]
+        if let Some(authenticator) = &mut self.layers.authenticator {
+            // a peer that authenticated turns into a `Connect` event, which starts the encryption's handshake
+            if !unsafe { authenticator.filter(&mut raw_event.sys_event, raw_event.connect_id) } {
+                return false;
+            }
+        }
+
+        #[cfg(feature = "encryption")]
+        if let Some(encryptor) = &mut self.layers.encryptor {
+            if !unsafe { encryptor.filter(&mut raw_event.sys_event, raw_event.connect_id) } {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Returns the next packet the `Encryptor` held back for a peer until its handshake completed.
+    #[cfg(feature = "encryption")]
+    fn released_event(&mut self) -> Option<RawEvent> {
+        let (sys_event, connect_id) = unsafe { self.layers.encryptor.as_mut()?.release(self.inner)? };
+
+        Some(RawEvent {
+            sys_event,
+            connect_id,
+            disconnect_kind: None,
+        })
+    }
+
+    /// Returns the next packet the `Authenticator` held back for a peer until it authenticated, which still passes
+    /// through the `Encryptor`.
+    #[cfg(feature = "auth")]
+    fn authenticated_event(&mut self) -> Option<RawEvent> {
+        let (sys_event, connect_id) = unsafe { self.layers.authenticator.as_mut()?.release(self.inner)? };
+
+        Some(RawEvent {
+            sys_event,
+            connect_id,
+            disconnect_kind: None,
+        })
+    }
+
+    /// Services this `Host` like `enet_host_service`, while delivering the datagrams held back by its
+    /// `NetworkConditioner` when they are due, which may be before an event occurs or the timeout expires.
+    fn service_conditioned(
+        &mut self,
+        conditioner: &SharedConditioner,
+        sys_event: *mut ENetEvent,
+        timeout_ms: u32,
+    ) -> c_int {
+        let inner = self.inner;
+        let socket = self.raw_socket();
+        let deadline = Instant::now() + Duration::from_millis(timeout_ms.into());
+
+        loop {
+            let now = Instant::now();
+            let next_due = Conditioner::lock(conditioner).release(socket, now);
+
+            let mut wait = deadline.saturating_duration_since(now);
+            if let Some(next_due) = next_due {
+                wait = wait.min(next_due.saturating_duration_since(now));
+            }
+            // round up, so the datagram is due once ENet returns
+            let wait_ms = wait.as_micros().div_ceil(1000) as u32;
+
+            let res = self
+                .intercept
+                .scope(inner, || unsafe { enet_host_service(inner, sys_event, wait_ms) });
+
+            if res != 0 || Instant::now() >= deadline {
+                return res;
+            }
+        }
+    }
+
+    /// Services this `Host` in a batch: waits up to `max_wait` for an event once, then drains all further
+    /// events without waiting until `budget` is spent, appending them to `out` as `OwnedEvent`s.
+    ///
+    /// Returns whether events may have been left behind because the budget ran out,
+    /// so a fixed-tick loop can bound the time it spends on a flood of datagrams.
+    /// On error, the events received so far are still in `out`.
+    pub fn service_batch(
+        &mut self,
+        max_wait: Duration,
+        budget: Duration,
+        out: &mut Vec<OwnedEvent>,
+    ) -> Result<bool, Error> {
+        let max_wait_ms = max_wait.as_millis().min(u32::MAX as u128) as u32;
+
+        match self.service(max_wait_ms)? {
+            Some(event) => out.push(event.into_owned()),
+            None => return Ok(false),
+        }
+
+        let start = Instant::now();
+        while start.elapsed() < budget {
+            match self.service(0)? {
+                Some(event) => out.push(event.into_owned()),
+                None => return Ok(false),
+            }
+        }
 
-        // TODO: check `total*` fields on `inner`, these need to be reset from time to time.
+        Ok(true)
     }
 
     /// Checks for any queued events on this `Host` and dispatches one if available
     pub fn check_events(&'_ mut self) -> Result<Option<Event<'_, T>>, Error> {
+        #[cfg(feature = "encryption")]
+        if let Some(event) = self.released_event() {
+            return Ok(self.deliver(event));
+        }
+
+        let event = self.check_events_raw()?;
+        #[cfg(any(feature = "auth", feature = "encryption"))]
+        let event = self.filter_events(event, 0, |host, _| host.check_events_raw())?;
+
+        Ok(event.and_then(|event| self.deliver(event)))
+    }
+
+    fn check_events_raw(&mut self) -> Result<Option<RawEvent>, Error> {
+        #[cfg(feature = "auth")]
+        if let Some(event) = self.authenticated_event() {
+            return Ok(Some(event));
+        }
+
         // ENetEvent is Copy (aka has no Drop impl), so we don't have to make sure we `mem::forget` it later on
         let mut sys_event = MaybeUninit::uninit();
 
+        self.snapshot_peers();
+
         let res = unsafe { enet_host_check_events(self.inner, sys_event.as_mut_ptr()) };
 
-        match res {
-            r if r > 0 => Ok(Event::from_sys_event(unsafe { &sys_event.assume_init() })),
-            0 => Ok(None),
-            r if r < 0 => Err(Error(r)),
-            _ => panic!("unreachable"),
-        }
+        self.raw_event(res, sys_event)
     }
 
     /// Initiates a connection to a foreign host.
     ///
     /// The connection will not be done until a `Event::Connected` for this peer was received.
+    /// The returned `PeerId` can be used to access the new peer through `Host::peer`/`Host::peer_mut`.
     ///
     /// `channel_count` specifies how many channels to allocate for this peer.
     /// `user_data` is a user-specified value that can be chosen arbitrarily.
@@ -228,7 +1228,7 @@ impl<T> Host<T> {
         address: &Address,
         channel_count: usize,
         user_data: u32,
-    ) -> Result<Peer<'_, T>, Error> {
+    ) -> Result<PeerId, Error> {
         let res: *mut ENetPeer = unsafe {
             enet_host_connect(
                 self.inner,
@@ -242,12 +1242,44 @@ impl<T> Host<T> {
             return Err(Error(0));
         }
 
-        Ok(Peer::new(res))
+        let id = Peer::<T>::new(res, &mut *self.layers).id();
+        self.peer_connect_ids[id.index()] = id.connect_id();
+        #[cfg(feature = "auth")]
+        if let Some(authenticator) = &mut self.layers.authenticator {
+            authenticator.connecting(id.index(), id.connect_id());
+        }
+        #[cfg(feature = "encryption")]
+        if let Some(encryptor) = &mut self.layers.encryptor {
+            encryptor.connecting(id.index(), id.connect_id());
+        }
+
+        Ok(id)
     }
 
-    /// Returns a wrapped socket
+    /// Returns the socket of this `Host`, e.g. to send raw datagrams or set socket options.
     pub fn socket(&mut self) -> Socket<T> {
-        Socket::new(unsafe { (*self.inner).socket })
+        Socket::new(self.raw_socket(), self.intercept.hooks.clone())
+    }
+
+    /// Destroys this `Host`, but keeps its socket open and returns it, e.g. to pass it on to another process.
+    ///
+    /// Peers are dropped without being notified, so they should be disconnected beforehand.
+    /// The socket is still in non-blocking mode.
+    pub fn into_udp_socket(self) -> UdpSocket {
+        // ENet does not close a null socket when destroying the host
+        let socket = unsafe { mem::replace(&mut (*self.inner).socket, ENET_SOCKET_NULL as _) };
+        drop(self);
+
+        #[cfg(unix)]
+        let socket = unsafe { UdpSocket::from_raw_fd(socket) };
+        #[cfg(windows)]
+        let socket = unsafe { UdpSocket::from_raw_socket(socket as _) };
+
+        socket
+    }
+
+    pub(in crate) fn raw_socket(&self) -> ENetSocket {
+        unsafe { (*self.inner).socket }
     }
 }
 
@@ -257,8 +1289,5 @@ impl<T> Drop for Host<T> {
         unsafe {
             enet_host_destroy(self.inner);
         }
-        if let Some(addr) = HOST_INTERCEPT_HANDLERS.lock().unwrap().remove(&(self.inner as usize)) {
-            let _: Box<Box<dyn FnMut(&mut Host<T>, Address, &[u8]) -> bool>> = unsafe { Box::from_raw(addr as *mut _) };
-        }
     }
 }
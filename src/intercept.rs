@@ -0,0 +1,309 @@
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    fmt,
+    os::raw::c_int,
+    panic::{self, AssertUnwindSafe},
+    ptr, slice,
+    time::Instant,
+};
+
+use log::{debug, error};
+
+use citizen_enet_sys::{ENetEvent, ENetHost, ENetSocket};
+
+use crate::{
+    capture::Capture,
+    conditioner::{Conditioner, Reception},
+    oob::{self, OobHandler},
+    socket::{Socket, SocketHooks},
+    Address, Error,
+};
+
+/// A handler for raw datagrams received by a `Host`, see `Host::set_intercept`.
+pub(crate) type InterceptHandler = Box<dyn FnMut(&mut InterceptContext<'_>) -> InterceptVerdict + Send>;
+
+/// What should happen to a datagram after it was intercepted.
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub enum InterceptVerdict {
+    /// The datagram is passed on to ENet's protocol handling.
+    Pass,
+    /// The datagram was handled by the intercept handler and is not passed on to ENet.
+    Consumed,
+    /// The datagram is unwanted and discarded without being passed on to ENet.
+    Drop,
+}
+
+/// What should happen when an intercept or OOB handler panics. Defaults to `InterceptPanicPolicy::Error`.
+///
+/// The panic itself is caught and logged in any case, as it must not unwind into ENet.
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, Default)]
+pub enum InterceptPanicPolicy {
+    /// `Host::service` aborts and returns an error.
+    #[default]
+    Error,
+    /// The datagram is passed on to ENet, as if the handler had returned `InterceptVerdict::Pass`.
+    Pass,
+    /// The datagram is discarded, as if the handler had returned `InterceptVerdict::Drop`.
+    Drop,
+}
+
+/// A raw datagram received by a `Host`, as seen by an intercept handler.
+pub struct InterceptContext<'a> {
+    address: Address,
+    data: &'a [u8],
+    received_at: Instant,
+    socket: ENetSocket,
+    hooks: SocketHooks,
+}
+
+impl<'a> InterceptContext<'a> {
+    /// Returns the address the datagram was received from.
+    pub fn address(&self) -> Address {
+        self.address
+    }
+
+    /// Returns the contents of the datagram.
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// Returns when the datagram was received, or rather when ENet handed it to the intercept handler.
+    pub fn received_at(&self) -> Instant {
+        self.received_at
+    }
+
+    /// Sends `data` as a single datagram back to the sender of the intercepted datagram,
+    /// returning the number of bytes sent.
+    pub fn reply(&mut self, data: &[u8]) -> Result<usize, Error> {
+        let address = self.address;
+        self.send_to(&address, data)
+    }
+
+    /// Sends `data` as a single datagram to `address` through the socket of the `Host`,
+    /// returning the number of bytes sent.
+    pub fn send_to(&mut self, address: &Address, data: &[u8]) -> Result<usize, Error> {
+        Socket::<()>::new(self.socket, self.hooks.clone())
+            .send_data(address, data)
+            .map(|sent| sent as usize)
+    }
+
+    /// Sends an out-of-band message back to the sender of the intercepted datagram, see `Host::send_oob`.
+    pub fn reply_oob(&mut self, command: &str, payload: &[u8]) -> Result<usize, Error> {
+        self.reply(&oob::encode(command, payload))
+    }
+}
+
+impl<'a> fmt::Debug for InterceptContext<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InterceptContext")
+            .field("address", &self.address)
+            .field("data_len", &self.data.len())
+            .field("received_at", &self.received_at)
+            .finish()
+    }
+}
+
+/// The intercept state of a `Host`, boxed so its address stays the same when the `Host` is moved.
+#[derive(Default)]
+pub(crate) struct Intercept {
+    pub(crate) handler: Option<InterceptHandler>,
+    pub(crate) oob_handlers: HashMap<String, OobHandler>,
+    pub(crate) panic_policy: InterceptPanicPolicy,
+    pub(crate) hooks: SocketHooks,
+}
+
+thread_local! {
+    // the host currently being serviced on this thread, and its intercept state
+    static CURRENT: Cell<(*mut ENetHost, *mut Intercept)> = const { Cell::new((ptr::null_mut(), ptr::null_mut())) };
+}
+
+impl Intercept {
+    /// Returns whether ENet has to call `callback` for received datagrams.
+    pub(crate) fn is_active(&self) -> bool {
+        self.handler.is_some()
+            || !self.oob_handlers.is_empty()
+            || self.hooks.conditioner.is_some()
+            || self.hooks.capture.is_some()
+    }
+
+    /// Runs `f`, which services `host`, making `self` available to `callback`.
+    ///
+    /// ENet's intercept callback does not carry any context, so the state is passed through a thread-local,
+    /// which works as ENet only calls it from within `enet_host_service` on the calling thread.
+    pub(crate) fn scope<R>(&mut self, host: *mut ENetHost, f: impl FnOnce() -> R) -> R {
+        let previous = CURRENT.with(|current| current.replace((host, self)));
+        let res = f();
+        CURRENT.with(|current| current.set(previous));
+        res
+    }
+
+    fn intercept(&mut self, host: *mut ENetHost) -> c_int {
+        let received_at = Instant::now();
+
+        if self.hooks.conditioner.is_some() || self.hooks.capture.is_some() {
+            let (address, data) = unsafe {
+                (
+                    Address::from_enet_address(&(*host).receivedAddress),
+                    slice::from_raw_parts((*host).receivedData, (*host).receivedDataLength),
+                )
+            };
+
+            let reception = match &self.hooks.conditioner {
+                Some(conditioner) => Conditioner::lock(conditioner).receive(address, data, received_at),
+                None => Reception::Deliver,
+            };
+
+            // datagrams injected by the conditioner were recorded when they were originally received
+            if let (Some(capture), false) = (&self.hooks.capture, matches!(reception, Reception::Replace(..))) {
+                Capture::lock(capture).record_received(address, data);
+            }
+
+            match reception {
+                Reception::Deliver => (),
+                Reception::Replace(original_sender, data) => {
+                    if !unsafe { replace(host, &original_sender, &data) } {
+                        return 1;
+                    }
+                }
+                Reception::Withhold => return 1,
+            }
+        }
+
+        let mut context = unsafe {
+            InterceptContext {
+                address: Address::from_enet_address(&(*host).receivedAddress),
+                data: slice::from_raw_parts((*host).receivedData, (*host).receivedDataLength),
+                received_at,
+                socket: (*host).socket,
+                hooks: self.hooks.clone(),
+            }
+        };
+
+        let verdict = match panic::catch_unwind(AssertUnwindSafe(|| self.dispatch(&mut context))) {
+            Ok(verdict) => verdict,
+            Err(err) => {
+                error!("panic in intercept handler: {}", panic_message(&*err));
+
+                match self.panic_policy {
+                    InterceptPanicPolicy::Error => return -1,
+                    InterceptPanicPolicy::Pass => InterceptVerdict::Pass,
+                    InterceptPanicPolicy::Drop => InterceptVerdict::Drop,
+                }
+            }
+        };
+
+        match verdict {
+            InterceptVerdict::Pass => 0,
+            InterceptVerdict::Consumed => 1,
+            InterceptVerdict::Drop => {
+                debug!("intercept handler dropped a datagram from {}", context.address);
+                1
+            }
+        }
+    }
+
+    /// Routes OOB datagrams to their command handler, and all others (including unknown OOB commands)
+    /// to the intercept handler.
+    fn dispatch(&mut self, context: &mut InterceptContext<'_>) -> InterceptVerdict {
+        if let Some((command, payload)) = oob::parse(context.data()) {
+            if let Some(handler) = self.oob_handlers.get_mut(command) {
+                handler(context, payload);
+                return InterceptVerdict::Consumed;
+            }
+        }
+
+        match &mut self.handler {
+            Some(handler) => handler(context),
+            None => InterceptVerdict::Pass,
+        }
+    }
+}
+
+/// Replaces a wake-up datagram of a `NetworkConditioner` with the datagram it held back,
+/// as if that had just been received from `original_sender`.
+///
+/// Returns `false` if the datagram does not fit into ENet's receive buffer, which only happens if it was already
+/// truncated when it was originally received.
+unsafe fn replace(host: *mut ENetHost, original_sender: &Address, data: &[u8]) -> bool {
+    let buffer = &mut (*host).packetData[0];
+    if data.len() > buffer.len() {
+        debug!("network conditioner dropped a datagram of {} bytes from {}", data.len(), original_sender);
+        return false;
+    }
+
+    // the wake-up is not counted, and the datagram was already counted when it was originally received
+    (*host).totalReceivedData = (*host).totalReceivedData.wrapping_sub((*host).receivedDataLength as u32);
+    (*host).totalReceivedPackets = (*host).totalReceivedPackets.wrapping_sub(1);
+
+    buffer[..data.len()].copy_from_slice(data);
+    (*host).receivedData = buffer.as_mut_ptr();
+    (*host).receivedDataLength = data.len();
+    (*host).receivedAddress = original_sender.enet_address();
+
+    true
+}
+
+fn panic_message(err: &(dyn std::any::Any + Send)) -> &str {
+    if let Some(message) = err.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = err.downcast_ref::<String>() {
+        message
+    } else {
+        "<non-string panic payload>"
+    }
+}
+
+/// The intercept callback installed on the `ENetHost` of a `Host` while it has handlers or hooks.
+pub(crate) unsafe extern "C" fn callback(host: *mut ENetHost, _event: *mut ENetEvent) -> c_int {
+    let (current_host, intercept) = CURRENT.with(Cell::get);
+
+    // only possible if the host is serviced without going through `Host::service`
+    if current_host != host || intercept.is_null() {
+        return 0;
+    }
+
+    (*intercept).intercept(host)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+        time::Duration,
+    };
+
+    use super::{InterceptPanicPolicy, InterceptVerdict};
+    use crate::{tests::ENET, Address};
+
+    #[test]
+    fn test_intercept_reply_and_panic_policy() {
+        let address = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 12370));
+        let mut host = ENET.host_builder().address(Address(address)).build::<()>().unwrap();
+
+        host.set_intercept(|context| match context.data() {
+            b"ping" => {
+                context.reply(b"pong").unwrap();
+                InterceptVerdict::Consumed
+            }
+            b"panic" => panic!("intercept handler panicked"),
+            _ => InterceptVerdict::Pass,
+        });
+
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+        socket.send_to(b"ping", address).unwrap();
+        assert!(host.service(100).unwrap().is_none());
+        let mut buffer = [0; 16];
+        let (len, _) = socket.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"pong");
+
+        socket.send_to(b"panic", address).unwrap();
+        assert!(host.service(100).is_err());
+
+        host.set_intercept_panic_policy(InterceptPanicPolicy::Drop);
+        socket.send_to(b"panic", address).unwrap();
+        assert!(host.service(100).unwrap().is_none());
+    }
+}
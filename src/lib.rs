@@ -40,6 +40,8 @@ extern crate failure_derive;
 extern crate lazy_static;
 
 use std::{
+    io,
+    net::UdpSocket,
     os::raw::c_int,
     sync::{
         atomic::{AtomicUsize, Ordering},
@@ -47,20 +49,56 @@ use std::{
     },
 };
 
-use citizen_enet_sys::{enet_deinitialize, enet_host_create, enet_initialize, enet_linked_version};
+use citizen_enet_sys::{enet_deinitialize, enet_initialize, enet_linked_version};
 
 mod address;
+#[cfg(feature = "auth")]
+mod auth;
+#[cfg(all(feature = "tokio", unix))]
+mod async_host;
+mod capture;
+mod checksum;
+mod compress;
+mod conditioner;
+#[cfg(feature = "encryption")]
+mod encryption;
 mod event;
 mod host;
+mod intercept;
+#[cfg(feature = "serde")]
+mod message;
+mod network_thread;
+mod oob;
 mod packet;
+pub mod protocol;
 mod socket;
 mod peer;
 
 pub use crate::address::Address;
-pub use crate::event::Event;
-pub use crate::host::{BandwidthLimit, ChannelLimit, Host};
-pub use crate::packet::{Packet, PacketMode};
-pub use crate::peer::{Peer, PeerPacket, PeerState};
+#[cfg(feature = "auth")]
+pub use crate::auth::{Authentication, AUTHENTICATION_FAILED};
+#[cfg(all(feature = "tokio", unix))]
+pub use crate::async_host::AsyncHost;
+pub use crate::checksum::{Checksum, ChecksumAlgorithm, CustomChecksum};
+pub use crate::compress::{Compression, CompressionStats, Compressor};
+pub use crate::conditioner::NetworkConditioner;
+#[cfg(feature = "encryption")]
+pub use crate::encryption::{Encryption, ENCRYPTION_FAILED};
+pub use crate::event::{DisconnectKind, Event, OwnedEvent};
+pub use crate::host::{BandwidthLimit, ChannelLimit, Host, HostBuilder, HostTraffic};
+pub use crate::intercept::{InterceptContext, InterceptPanicPolicy, InterceptVerdict};
+#[cfg(feature = "bincode")]
+pub use crate::message::Bincode;
+#[cfg(feature = "serde")]
+pub use crate::message::Codec;
+#[cfg(feature = "json")]
+pub use crate::message::Json;
+#[cfg(feature = "postcard")]
+pub use crate::message::Postcard;
+pub use crate::network_thread::{HostHandle, NetworkThread};
+pub use crate::oob::OOB_PREFIX;
+pub use crate::packet::{Packet, PacketFlags, PacketMode};
+pub use crate::peer::{Peer, PeerId, PeerPacket, PeerRef, PeerState, PeerStats};
 pub use crate::peer::PACKET_THROTTLE_SCALE as PEER_PACKET_THROTTLE_SCALE;
 
 pub use citizen_enet_sys::ENetVersion as EnetVersion;
@@ -107,6 +145,120 @@ pub enum InitializationError {
     Error(c_int),
 }
 
+/// An error that can occur when creating a `Host`.
+#[derive(Fail, Debug)]
+pub enum HostCreationError {
+    /// A setting of the `HostBuilder` is outside of the range supported by ENet.
+    #[fail(display = "invalid value {} for `{}`, must be between {} and {}", value, setting, min, max)]
+    InvalidSetting {
+        /// The name of the invalid setting.
+        setting: &'static str,
+        /// The value the setting was set to.
+        value: usize,
+        /// The minimum allowed value of the setting.
+        min: usize,
+        /// The maximum allowed value of the setting.
+        max: usize,
+    },
+    /// The socket of the host could not be created or bound to the requested address.
+    #[fail(display = "could not create host bound to {}: {}", address, error)]
+    BindFailed {
+        /// The address the host should have been bound to.
+        address: Address,
+        /// The underlying OS error.
+        #[cause]
+        error: io::Error,
+    },
+    /// The host or its socket could not be created (`enet_host_create` failed), containing the underlying OS error.
+    #[fail(display = "could not create host: {}", _0)]
+    CreateFailed(#[cause] io::Error),
+    /// The compression requested for the host could not be set up.
+    #[fail(display = "could not set up compression for host: {}", _0)]
+    CompressionFailed(#[cause] CompressionError),
+    /// The socket passed to `HostBuilder::socket` cannot be used by ENet, e.g. because it is not an IPv6 socket.
+    #[fail(display = "invalid socket for host: {}", _0)]
+    InvalidSocket(#[cause] io::Error),
+}
+
+/// An error that can occur when setting the compression of a `Host`, see `Host::set_compressor`.
+#[derive(Fail, Debug)]
+pub enum CompressionError {
+    /// ENet could not allocate the state of its range coder (`enet_range_coder_create` failed).
+    #[fail(display = "could not create the range coder")]
+    RangeCoderCreationFailed,
+}
+
+/// An error that can occur when operating on a peer through its `PeerId`.
+#[derive(Fail, Debug)]
+pub enum PeerError {
+    /// The peer is gone: its connection ended, and its slot was reset or reused.
+    #[fail(display = "peer {:?} is not connected anymore", _0)]
+    Gone(PeerId),
+    /// The peer did not authenticate yet, so it cannot be sent any packets, see `Host::set_authentication`.
+    #[fail(display = "peer {:?} did not authenticate yet", _0)]
+    Unauthenticated(PeerId),
+    /// The underlying ENet operation failed.
+    #[fail(display = "{}", _0)]
+    Enet(#[cause] Error),
+}
+
+/// An error that can occur when controlling a `Host` through a `HostHandle`.
+#[derive(Fail, Debug)]
+pub enum HostHandleError {
+    /// The `NetworkThread` owning the host has stopped.
+    #[fail(display = "the network thread has stopped")]
+    Stopped,
+    /// The underlying ENet operation failed.
+    #[fail(display = "{}", _0)]
+    Enet(#[cause] Error),
+}
+
+/// An error that can occur when encoding a message into a packet or sending it, see `Peer::send_message`.
+///
+/// Requires the `serde` feature.
+#[cfg(feature = "serde")]
+#[derive(Fail, Debug)]
+pub enum EncodeError<E: failure::Fail> {
+    /// The codec could not serialize the message.
+    #[fail(display = "could not encode message: {}", _0)]
+    Codec(#[cause] E),
+    /// The packet could not be created.
+    #[fail(display = "{}", _0)]
+    Enet(#[cause] Error),
+    /// The packet could not be sent to the peer.
+    #[fail(display = "{}", _0)]
+    Peer(#[cause] PeerError),
+}
+
+/// An error that can occur when decoding a message from a packet, see `Packet::decode`.
+///
+/// Requires the `serde` feature.
+#[cfg(feature = "serde")]
+#[derive(Fail, Debug)]
+pub struct DecodeError<E: failure::Fail> {
+    /// The peer the packet was received from, or `None` if it was not received from a peer.
+    pub peer: Option<PeerId>,
+    /// The channel the packet was received on, or `None` if it was not received from a peer.
+    pub channel_id: Option<u8>,
+    /// The error returned by the codec.
+    #[cause]
+    pub error: E,
+}
+
+#[cfg(feature = "serde")]
+impl<E: failure::Fail> std::fmt::Display for DecodeError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.peer, self.channel_id) {
+            (Some(peer), Some(channel_id)) => write!(
+                f,
+                "could not decode message from peer {:?} on channel {}: {}",
+                peer, channel_id, self.error
+            ),
+            _ => write!(f, "could not decode message: {}", self.error),
+        }
+    }
+}
+
 impl Enet {
     /// Initializes ENet and returns a handle to the top-level functionality, in the form of an `Enet`-instance.
     pub fn new() -> Result<Enet, InitializationError> {
@@ -138,6 +290,8 @@ impl Enet {
     /// `max_channel_count` will be set to its (ENet-specified) default value if `None`.
     ///
     /// The type `T` specifies the data associated with corresponding `Peer`s.
+    ///
+    /// Use `host_builder()` to access further settings of the host.
     pub fn create_host<T>(
         &self,
         address: Option<&Address>,
@@ -145,25 +299,46 @@ impl Enet {
         max_channel_count: ChannelLimit,
         incoming_bandwidth: BandwidthLimit,
         outgoing_bandwidth: BandwidthLimit,
-    ) -> Result<Host<T>, Error> {
-        let addr = address.map(Address::enet_address);
-        let inner = unsafe {
-            enet_host_create(
-                addr.as_ref()
-                    .map(|p| p as *const _)
-                    .unwrap_or(std::ptr::null()),
-                max_peer_count,
-                max_channel_count.to_enet_usize(),
-                incoming_bandwidth.to_enet_u32(),
-                outgoing_bandwidth.to_enet_u32(),
-            )
-        };
+    ) -> Result<Host<T>, HostCreationError> {
+        let mut builder = self
+            .host_builder()
+            .peer_limit(max_peer_count)
+            .channel_limit(max_channel_count)
+            .incoming_bandwidth(incoming_bandwidth)
+            .outgoing_bandwidth(outgoing_bandwidth);
 
-        if inner.is_null() {
-            return Err(Error(0));
+        if let Some(address) = address {
+            builder = builder.address(*address);
         }
 
-        Ok(Host::new(self.keep_alive.clone(), inner))
+        builder.build()
+    }
+
+    /// Creates a `Host` that adopts the already bound `socket`, instead of creating and binding its own.
+    ///
+    /// ENet's sockets are dual-stack IPv6 sockets, so `socket` has to be an IPv6 socket.
+    /// It is made non-blocking, but its other options are left as they are.
+    /// See `create_host` for the other arguments, and `Host::into_udp_socket` to reclaim the socket.
+    pub fn create_host_from_socket<T>(
+        &self,
+        socket: UdpSocket,
+        max_peer_count: usize,
+        max_channel_count: ChannelLimit,
+        incoming_bandwidth: BandwidthLimit,
+        outgoing_bandwidth: BandwidthLimit,
+    ) -> Result<Host<T>, HostCreationError> {
+        self.host_builder()
+            .socket(socket)
+            .peer_limit(max_peer_count)
+            .channel_limit(max_channel_count)
+            .incoming_bandwidth(incoming_bandwidth)
+            .outgoing_bandwidth(outgoing_bandwidth)
+            .build()
+    }
+
+    /// Returns a `HostBuilder`, which allows creating a `Host` with custom settings.
+    pub fn host_builder(&self) -> HostBuilder {
+        HostBuilder::new(self.keep_alive.clone())
     }
 }
 
@@ -190,12 +365,64 @@ impl Drop for EnetKeepAlive {
 
 #[cfg(test)]
 mod tests {
-    use std::net::{IpAddr, SocketAddr, SocketAddrV4};
+    use std::{
+        net::{IpAddr, SocketAddr, SocketAddrV4},
+        time::{Duration, Instant},
+    };
 
     use super::{BandwidthLimit, ChannelLimit, Enet};
+    use crate::{Address, Event, Host, PeerId, PeerState};
 
     lazy_static! {
-        static ref ENET: Enet = Enet::new().unwrap();
+        pub(crate) static ref ENET: Enet = Enet::new().unwrap();
+    }
+
+    /// Returns the loopback address with `port`.
+    pub(crate) fn localhost(port: u16) -> Address {
+        Address(SocketAddr::V4(SocketAddrV4::new(std::net::Ipv4Addr::LOCALHOST, port)))
+    }
+
+    /// Calls `step` until it returns `Some`, returning `None` if that did not happen within `timeout`.
+    pub(crate) fn service_until<R>(timeout: Duration, mut step: impl FnMut() -> Option<R>) -> Option<R> {
+        let deadline = Instant::now() + timeout;
+
+        while Instant::now() < deadline {
+            if let Some(res) = step() {
+                return Some(res);
+            }
+        }
+
+        None
+    }
+
+    /// Connects `client` to `server` at `address` with 2 channels, and services both until they returned their
+    /// `Connect` events, returning the `PeerId`s of the connection on the server and on the client.
+    pub(crate) fn connect_hosts(server: &mut Host<()>, client: &mut Host<()>, address: &Address) -> (PeerId, PeerId) {
+        let client_peer = client.connect(address, 2, 0).unwrap();
+        let (mut server_peer, mut client_connected) = (None, false);
+
+        service_until(Duration::from_secs(2), || {
+            if let Some(Event::Connect(ref peer)) = client.service(0).unwrap() {
+                client_connected |= peer.id() == client_peer;
+            }
+            if let Some(Event::Connect(ref peer)) = server.service(5).unwrap() {
+                server_peer = Some(peer.id());
+            }
+            server_peer.filter(|_| client_connected)
+        })
+        .map(|server_peer| (server_peer, client_peer))
+        .expect("connection timed out")
+    }
+
+    /// Creates a server on localhost:`port` and a client connected to it, see `connect_hosts`,
+    /// returning both and the `PeerId` of the server on the client.
+    pub(crate) fn connect_pair(port: u16) -> (Host<()>, Host<()>, PeerId) {
+        let address = localhost(port);
+        let mut server = ENET.host_builder().address(address).build::<()>().unwrap();
+        let mut client = ENET.host_builder().build::<()>().unwrap();
+
+        let (_, peer) = connect_hosts(&mut server, &mut client, &address);
+        (server, client, peer)
     }
 
     #[test]
@@ -219,4 +446,218 @@ mod tests {
         )
         .unwrap();
     }
+
+    #[test]
+    fn test_host_builder_settings() {
+        use crate::{Checksum, Compression};
+
+        let host = ENET
+            .host_builder()
+            .peer_limit(4)
+            .mtu(1200)
+            .duplicate_peers(2)
+            .maximum_packet_size(1 << 16)
+            .maximum_waiting_data(1 << 20)
+            .checksum(Checksum::Crc32)
+            .compression(Compression::RangeCoder)
+            .build::<()>()
+            .unwrap();
+
+        assert_eq!(host.peer_count(), 4);
+        assert_eq!(host.mtu(), 1200);
+        assert_eq!(host.duplicate_peers(), 2);
+        assert_eq!(host.maximum_packet_size(), 1 << 16);
+        assert_eq!(host.maximum_waiting_data(), 1 << 20);
+    }
+
+    #[test]
+    fn test_host_builder_invalid_setting() {
+        use crate::HostCreationError;
+
+        match ENET.host_builder().mtu(100).build::<()>() {
+            Err(HostCreationError::InvalidSetting { setting: "mtu", value: 100, .. }) => (),
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_host_traffic() {
+        let (server, mut client, _) = connect_pair(12347);
+
+        let traffic = client.traffic();
+        assert!(traffic.sent_packets > 0 && traffic.sent_bytes > 0);
+        assert!(traffic.received_packets > 0 && traffic.received_bytes > 0);
+        assert!(server.traffic().received_packets > 0);
+
+        client.reset_traffic();
+        assert_eq!(client.traffic(), Default::default());
+    }
+
+    #[test]
+    fn test_broadcast_and_multicast() {
+        use crate::{Packet, PacketMode};
+
+        let address = localhost(12349);
+        let mut server = ENET.host_builder().address(address).peer_limit(2).build::<()>().unwrap();
+        let mut clients = [
+            ENET.host_builder().build::<()>().unwrap(),
+            ENET.host_builder().build::<()>().unwrap(),
+        ];
+
+        let connected: Vec<_> = clients
+            .iter_mut()
+            .map(|client| connect_hosts(&mut server, client, &address).0)
+            .collect();
+
+        server.broadcast(Packet::new(b"all", PacketMode::ReliableSequenced).unwrap(), 0);
+        let first = Packet::new(b"first", PacketMode::ReliableSequenced).unwrap();
+        assert_eq!(server.multicast(first, 0, |peer| peer.id() == connected[0]), 1);
+        server.flush();
+
+        let mut received = [Vec::new(), Vec::new()];
+        service_until(Duration::from_secs(1), || {
+            server.service(0).unwrap();
+            for (client, received) in clients.iter_mut().zip(&mut received) {
+                if let Some(Event::Receive { ref packet, .. }) = client.service(5).unwrap() {
+                    received.push(packet.data().to_vec());
+                }
+            }
+            Some(()).filter(|_| received[0].len() + received[1].len() == 3)
+        })
+        .expect("receiving timed out");
+
+        received.sort_by_key(Vec::len);
+        assert_eq!(received[0], [b"all".to_vec()]);
+        assert_eq!(received[1], [b"all".to_vec(), b"first".to_vec()]);
+    }
+
+    #[test]
+    fn test_host_from_socket() {
+        use crate::HostCreationError;
+        use std::net::{Ipv4Addr, Ipv6Addr, UdpSocket};
+
+        let socket = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).unwrap();
+        let local_addr = socket.local_addr().unwrap();
+
+        let host = ENET.host_builder().socket(socket).build::<()>().unwrap();
+        assert_eq!(host.local_addr().unwrap().port(), local_addr.port());
+        assert_eq!(host.into_udp_socket().local_addr().unwrap(), local_addr);
+
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        match ENET.host_builder().socket(socket).build::<()>() {
+            Err(HostCreationError::InvalidSocket(_)) => (),
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_disconnect_kinds() {
+        use crate::{DisconnectKind, Packet, PacketMode};
+
+        let (mut server, mut client, client_peer) = connect_pair(12372);
+        client.peer_mut(client_peer).unwrap().disconnect(7);
+
+        let (mut server_kind, mut client_kind) = (None, None);
+        service_until(Duration::from_secs(1), || {
+            if let Some(Event::Disconnect(_, data, kind)) = server.service(0).unwrap() {
+                server_kind = Some((data, kind));
+            }
+            if let Some(Event::Disconnect(_, _, kind)) = client.service(5).unwrap() {
+                client_kind = Some(kind);
+            }
+            server_kind.zip(client_kind)
+        })
+        .expect("disconnection timed out");
+        assert_eq!(server_kind, Some((7, DisconnectKind::Graceful)));
+        assert_eq!(client_kind, Some(DisconnectKind::LocalRequest));
+
+        let (server_peer, _) = connect_hosts(&mut server, &mut client, &localhost(12372));
+        drop(client);
+
+        let mut peer = server.peer_mut(server_peer).unwrap();
+        peer.set_timeout(1, 100, 200);
+        peer.send_packet(Packet::new(b"anyone?", PacketMode::ReliableSequenced).unwrap(), 0)
+            .unwrap();
+
+        let disconnect = service_until(Duration::from_secs(2), || match server.service(5).unwrap() {
+            Some(Event::Disconnect(_, data, kind)) => Some((data, kind)),
+            _ => None,
+        });
+        assert_eq!(disconnect, Some((0, DisconnectKind::Timeout)));
+    }
+
+    #[test]
+    fn test_service_batch() {
+        use crate::{Packet, PacketMode};
+
+        let (mut server, mut client, peer) = connect_pair(12374);
+
+        for _ in 0..10 {
+            let packet = Packet::new(b"flood", PacketMode::ReliableSequenced).unwrap();
+            client.peer_mut(peer).unwrap().send_packet(packet, 0).unwrap();
+        }
+        client.flush();
+        std::thread::sleep(Duration::from_millis(50));
+
+        // the 10 packets are waiting now
+        let mut events = Vec::new();
+        assert!(server.service_batch(Duration::from_millis(100), Duration::ZERO, &mut events).unwrap());
+        assert_eq!(events.len(), 1);
+
+        assert!(!server.service_batch(Duration::ZERO, Duration::from_secs(1), &mut events).unwrap());
+        assert_eq!(events.len(), 10);
+    }
+
+    #[test]
+    fn test_peer_id_after_reset() {
+        use crate::Address;
+        use std::net::Ipv4Addr;
+
+        let enet = &ENET;
+        let mut host = enet
+            .create_host::<()>(
+                None,
+                1,
+                ChannelLimit::Maximum,
+                BandwidthLimit::Unlimited,
+                BandwidthLimit::Unlimited,
+            )
+            .unwrap();
+
+        let peer_id = host
+            .connect(&Address(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 12346))), 1, 0)
+            .unwrap();
+        assert_eq!(host.peer_mut(peer_id).unwrap().id(), peer_id);
+
+        host.peer_mut(peer_id).unwrap().reset();
+        assert!(host.peer_mut(peer_id).is_none());
+    }
+
+    #[test]
+    fn test_peer_id_after_reuse() {
+        let address = localhost(12384);
+        let mut server = ENET.host_builder().address(address).peer_limit(1).build::<()>().unwrap();
+        let mut client = ENET.host_builder().build::<()>().unwrap();
+
+        let (old_peer, client_peer) = connect_hosts(&mut server, &mut client, &address);
+        client.peer_mut(client_peer).unwrap().disconnect(0);
+
+        let disconnected = service_until(Duration::from_secs(1), || {
+            client.service(0).unwrap();
+            match server.service(5).unwrap() {
+                Some(Event::Disconnect(ref peer, ..)) => Some(peer.id()),
+                _ => None,
+            }
+        });
+        assert_eq!(disconnected, Some(old_peer));
+        assert!(server.peer(old_peer).is_none());
+
+        // the only slot is reused by the next connection, which the old `PeerId` must not resolve to
+        let (new_peer, _) = connect_hosts(&mut server, &mut client, &address);
+        assert_eq!(new_peer.index(), old_peer.index());
+        assert!(server.peer(old_peer).is_none());
+        assert!(server.peer_mut(old_peer).is_none());
+        assert_eq!(server.peer(new_peer).unwrap().id(), new_peer);
+        assert_eq!(server.peer(new_peer).unwrap().state(), PeerState::Connected);
+    }
 }
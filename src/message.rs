@@ -0,0 +1,208 @@
+use std::io;
+
+use failure::Fail;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{DecodeError, EncodeError, Packet, PacketMode, Peer};
+
+/// A serialization format for messages sent with `Peer::send_message` and received with `Packet::decode`.
+///
+/// Implemented by `Bincode`, `Postcard` and `Json` with the features of the same names (`json` for `Json`).
+/// Both ends of a connection have to agree on the codec, ENet does not record it.
+pub trait Codec {
+    /// The error returned when encoding or decoding fails.
+    type Error: Fail;
+
+    /// Serializes `message` into `writer`.
+    fn encode<M: Serialize + ?Sized, W: io::Write>(&self, message: &M, writer: W) -> Result<(), Self::Error>;
+
+    /// Deserializes a message from `data`.
+    fn decode<M: DeserializeOwned>(&self, data: &[u8]) -> Result<M, Self::Error>;
+}
+
+/// The [bincode](https://crates.io/crates/bincode) format with its default options. Requires the `bincode` feature.
+#[cfg(feature = "bincode")]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl Codec for Bincode {
+    type Error = bincode::Error;
+
+    fn encode<M: Serialize + ?Sized, W: io::Write>(&self, message: &M, writer: W) -> Result<(), Self::Error> {
+        bincode::serialize_into(writer, message)
+    }
+
+    fn decode<M: DeserializeOwned>(&self, data: &[u8]) -> Result<M, Self::Error> {
+        bincode::deserialize(data)
+    }
+}
+
+/// The compact [postcard](https://crates.io/crates/postcard) format. Requires the `postcard` feature.
+#[cfg(feature = "postcard")]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Postcard;
+
+#[cfg(feature = "postcard")]
+impl Codec for Postcard {
+    type Error = postcard::Error;
+
+    fn encode<M: Serialize + ?Sized, W: io::Write>(&self, message: &M, writer: W) -> Result<(), Self::Error> {
+        postcard::to_io(message, writer).map(|_| ())
+    }
+
+    fn decode<M: DeserializeOwned>(&self, data: &[u8]) -> Result<M, Self::Error> {
+        postcard::from_bytes(data)
+    }
+}
+
+/// JSON, through [serde_json](https://crates.io/crates/serde_json). Requires the `json` feature.
+///
+/// Much larger than the binary formats, but easy to inspect and to produce from other languages.
+#[cfg(feature = "json")]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl Codec for Json {
+    type Error = serde_json::Error;
+
+    fn encode<M: Serialize + ?Sized, W: io::Write>(&self, message: &M, writer: W) -> Result<(), Self::Error> {
+        serde_json::to_writer(writer, message)
+    }
+
+    fn decode<M: DeserializeOwned>(&self, data: &[u8]) -> Result<M, Self::Error> {
+        serde_json::from_slice(data)
+    }
+}
+
+impl Packet {
+    /// Creates a new Packet containing `message`, serialized with `codec`. Requires the `serde` feature.
+    pub fn encode<M: Serialize + ?Sized, C: Codec>(
+        message: &M,
+        mode: PacketMode,
+        codec: C,
+    ) -> Result<Packet, EncodeError<C::Error>> {
+        let mut data = Vec::new();
+        codec.encode(message, &mut data).map_err(EncodeError::Codec)?;
+
+        Packet::from_vec(data, mode).map_err(EncodeError::Enet)
+    }
+
+    /// Deserializes the contents of this packet with `codec`. Requires the `serde` feature.
+    ///
+    /// If the packet was received from a peer, a `DecodeError` names that peer and the channel
+    /// the packet was received on.
+    pub fn decode<M: DeserializeOwned, C: Codec>(&self, codec: C) -> Result<M, DecodeError<C::Error>> {
+        codec.decode(self.data()).map_err(|error| DecodeError {
+            peer: self.origin.map(|(peer, _)| peer),
+            channel_id: self.origin.map(|(_, channel_id)| channel_id),
+            error,
+        })
+    }
+}
+
+impl<'a, T> Peer<'a, T> {
+    /// Serializes `message` with `codec` and queues it to be sent to this peer on channel `channel_id`,
+    /// see `Packet::encode` and `Peer::send_packet`. Requires the `serde` feature.
+    pub fn send_message<M: Serialize + ?Sized, C: Codec>(
+        &mut self,
+        message: &M,
+        channel_id: u8,
+        mode: PacketMode,
+        codec: C,
+    ) -> Result<(), EncodeError<C::Error>> {
+        let packet = Packet::encode(message, mode, codec)?;
+
+        self.send_packet(packet, channel_id).map_err(EncodeError::Peer)
+    }
+}
+
+#[cfg(all(test, any(feature = "bincode", feature = "postcard", feature = "json")))]
+mod tests {
+    #[cfg(feature = "json")]
+    use std::time::Duration;
+
+    use serde::{Deserialize, Serialize};
+
+    #[cfg(feature = "bincode")]
+    use super::Bincode;
+    #[cfg(feature = "json")]
+    use super::Json;
+    #[cfg(feature = "postcard")]
+    use super::Postcard;
+    #[cfg(feature = "json")]
+    use crate::{
+        tests::{connect_pair, service_until},
+        OwnedEvent,
+    };
+    use crate::{Codec, Packet, PacketMode};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Chat {
+        from: String,
+        text: String,
+    }
+
+    fn chat() -> Chat {
+        Chat {
+            from: "client".into(),
+            text: "hello".into(),
+        }
+    }
+
+    fn assert_round_trip<C: Codec + Copy>(codec: C) {
+        let packet = Packet::encode(&chat(), PacketMode::ReliableSequenced, codec).unwrap();
+        assert_eq!(packet.decode::<Chat, _>(codec).unwrap(), chat());
+
+        let truncated = &packet.data()[..packet.data().len() - 1];
+        assert!(codec.decode::<Chat>(truncated).is_err());
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn test_bincode_round_trip() {
+        assert_round_trip(Bincode);
+    }
+
+    #[cfg(feature = "postcard")]
+    #[test]
+    fn test_postcard_round_trip() {
+        assert_round_trip(Postcard);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_json_round_trip() {
+        assert_round_trip(Json);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_send_and_decode_message() {
+        let (mut server, mut client, peer) = connect_pair(12375);
+        let message = chat();
+
+        let mut p = client.peer_mut(peer).unwrap();
+        p.send_message(&message, 1, PacketMode::ReliableSequenced, Json).unwrap();
+        client.flush();
+
+        let (sender, packet) = service_until(Duration::from_secs(1), || {
+            match server.service(5).unwrap().map(OwnedEvent::from) {
+                Some(OwnedEvent::Receive { peer, packet, .. }) => Some((peer, packet)),
+                _ => None,
+            }
+        })
+        .expect("receiving timed out");
+
+        assert_eq!(packet.decode::<Chat, _>(Json).unwrap(), message);
+
+        let err = packet.decode::<u32, _>(Json).unwrap_err();
+        assert_eq!(err.peer, Some(sender));
+        assert_eq!(err.channel_id, Some(1));
+
+        let local = Packet::encode(&message, PacketMode::UnreliableSequenced, Json).unwrap();
+        assert_eq!(local.decode::<Chat, _>(Json).unwrap(), message);
+        assert_eq!(local.decode::<u32, _>(Json).unwrap_err().peer, None);
+    }
+}
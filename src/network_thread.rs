@@ -0,0 +1,321 @@
+use std::{
+    io,
+    sync::mpsc::{self, Receiver, Sender, TryRecvError},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use log::warn;
+
+use crate::{Address, Error, Event, Host, HostHandleError, OwnedEvent, Packet, PeerId};
+
+/// Default timeout for servicing the host, which bounds how long commands wait before they are applied.
+const DEFAULT_POLL_TIMEOUT: Duration = Duration::from_millis(1);
+
+enum Command {
+    Send {
+        peer: PeerId,
+        channel_id: u8,
+        packet: Packet,
+    },
+    Broadcast {
+        channel_id: u8,
+        packet: Packet,
+    },
+    Disconnect {
+        peer: PeerId,
+        user_data: u32,
+    },
+    Connect {
+        address: Address,
+        channel_count: usize,
+        user_data: u32,
+        reply: Sender<Result<PeerId, Error>>,
+    },
+    Shutdown,
+}
+
+/// A thread that owns a `Host` and services it in a loop.
+///
+/// The host is controlled through `HostHandle`s, which can be cloned and used from any thread,
+/// and its events are delivered as `OwnedEvent`s through a channel.
+///
+/// Dropping the `NetworkThread` shuts the thread down and waits for it to finish.
+pub struct NetworkThread {
+    handle: HostHandle,
+    thread: Option<JoinHandle<Result<(), Error>>>,
+}
+
+/// A handle to control the `Host` owned by a `NetworkThread`.
+///
+/// Commands are applied by the network thread on its next iteration, and the host is flushed afterwards.
+/// Commands for peers that are gone by then are dropped with a warning.
+#[derive(Clone, Debug)]
+pub struct HostHandle {
+    commands: Sender<Command>,
+}
+
+impl NetworkThread {
+    /// Spawns a thread that owns and services `host`.
+    ///
+    /// Returns the `NetworkThread`, a `HostHandle` to control the host, and the receiving end of its events.
+    pub fn spawn<T: Send + 'static>(host: Host<T>) -> io::Result<(NetworkThread, HostHandle, Receiver<OwnedEvent>)> {
+        NetworkThread::spawn_with_poll_timeout(host, DEFAULT_POLL_TIMEOUT)
+    }
+
+    /// Like `spawn`, but services the host with the given timeout (1ms by default).
+    ///
+    /// The timeout bounds how long commands sent through a `HostHandle` wait before they are applied.
+    pub fn spawn_with_poll_timeout<T: Send + 'static>(
+        host: Host<T>,
+        poll_timeout: Duration,
+    ) -> io::Result<(NetworkThread, HostHandle, Receiver<OwnedEvent>)> {
+        let (command_sender, commands) = mpsc::channel();
+        let (event_sender, events) = mpsc::channel();
+
+        let thread = thread::Builder::new()
+            .name("enet-network".into())
+            .spawn(move || run(host, commands, event_sender, poll_timeout))?;
+
+        let handle = HostHandle {
+            commands: command_sender,
+        };
+
+        let network_thread = NetworkThread {
+            handle: handle.clone(),
+            thread: Some(thread),
+        };
+
+        Ok((network_thread, handle, events))
+    }
+
+    /// Returns a new `HostHandle` for the host of this thread.
+    pub fn handle(&self) -> HostHandle {
+        self.handle.clone()
+    }
+
+    /// Shuts the thread down, dropping its `Host`, and returns the error that stopped it early, if any.
+    pub fn shutdown(mut self) -> Result<(), Error> {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> Result<(), Error> {
+        let thread = match self.thread.take() {
+            Some(thread) => thread,
+            None => return Ok(()),
+        };
+
+        // the thread might already have stopped because of an error
+        let _ = self.handle.commands.send(Command::Shutdown);
+
+        match thread.join() {
+            Ok(result) => result,
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    }
+}
+
+impl Drop for NetworkThread {
+    fn drop(&mut self) {
+        if let Err(err) = self.stop() {
+            warn!("network thread stopped with an error: {}", err);
+        }
+    }
+}
+
+impl HostHandle {
+    fn command(&self, command: Command) -> Result<(), HostHandleError> {
+        self.commands.send(command).map_err(|_| HostHandleError::Stopped)
+    }
+
+    /// Queues `packet` to be sent to the peer `peer` on channel `channel_id`.
+    pub fn send(&self, peer: PeerId, channel_id: u8, packet: Packet) -> Result<(), HostHandleError> {
+        self.command(Command::Send {
+            peer,
+            channel_id,
+            packet,
+        })
+    }
+
+    /// Queues `packet` to be sent to all connected peers on channel `channel_id`.
+    pub fn broadcast(&self, channel_id: u8, packet: Packet) -> Result<(), HostHandleError> {
+        self.command(Command::Broadcast { channel_id, packet })
+    }
+
+    /// Disconnects from the peer `peer`, see `Peer::disconnect`.
+    pub fn disconnect(&self, peer: PeerId, user_data: u32) -> Result<(), HostHandleError> {
+        self.command(Command::Disconnect { peer, user_data })
+    }
+
+    /// Initiates a connection to a foreign host, see `Host::connect`.
+    ///
+    /// Blocks until the network thread has handled the request, which does not wait for the connection to be
+    /// established; that is signaled by an `OwnedEvent::Connect` for the returned `PeerId`.
+    pub fn connect(&self, address: &Address, channel_count: usize, user_data: u32) -> Result<PeerId, HostHandleError> {
+        let (reply, result) = mpsc::channel();
+
+        self.command(Command::Connect {
+            address: *address,
+            channel_count,
+            user_data,
+            reply,
+        })?;
+
+        result
+            .recv()
+            .map_err(|_| HostHandleError::Stopped)?
+            .map_err(HostHandleError::Enet)
+    }
+}
+
+fn run<T>(
+    mut host: Host<T>,
+    commands: Receiver<Command>,
+    events: Sender<OwnedEvent>,
+    poll_timeout: Duration,
+) -> Result<(), Error> {
+    let poll_timeout_ms = poll_timeout.as_millis().min(u32::MAX as u128) as u32;
+
+    loop {
+        let mut applied = false;
+
+        loop {
+            let command = match commands.try_recv() {
+                Ok(Command::Shutdown) | Err(TryRecvError::Disconnected) => return Ok(()),
+                Ok(command) => command,
+                Err(TryRecvError::Empty) => break,
+            };
+
+            apply(&mut host, command);
+            applied = true;
+        }
+
+        if applied {
+            host.flush();
+        }
+
+        // Service only once per iteration, so a peer flooding the host cannot starve the commands,
+        // then deliver the events that service already received without reading the socket again.
+        let mut event = host.service(poll_timeout_ms)?.map(Event::into_owned);
+        while let Some(owned) = event {
+            // nobody might be listening anymore, but the host still has to be serviced
+            let _ = events.send(owned);
+            event = host.check_events()?.map(Event::into_owned);
+        }
+    }
+}
+
+fn apply<T>(host: &mut Host<T>, command: Command) {
+    match command {
+        Command::Send {
+            peer,
+            channel_id,
+            packet,
+        } => match host.peer_mut(peer) {
+            Some(mut p) => {
+                if let Err(err) = p.send_packet(packet, channel_id) {
+                    warn!("network thread: sending to peer {:?} failed: {}", peer, err);
+                }
+            }
+            None => warn!("network thread: dropping packet for peer {:?}, which is gone", peer),
+        },
+        Command::Broadcast { channel_id, packet } => host.broadcast(packet, channel_id),
+        Command::Disconnect { peer, user_data } => match host.peer_mut(peer) {
+            Some(mut p) => p.disconnect(user_data),
+            None => warn!("network thread: cannot disconnect peer {:?}, which is gone", peer),
+        },
+        Command::Connect {
+            address,
+            channel_count,
+            user_data,
+            reply,
+        } => {
+            let _ = reply.send(host.connect(&address, channel_count, user_data));
+        }
+        Command::Shutdown => unreachable!("handled by the service loop"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+        time::Duration,
+    };
+
+    use super::NetworkThread;
+    use crate::{
+        tests::{localhost, service_until, ENET},
+        Address, Event, OwnedEvent, Packet, PacketMode,
+    };
+
+    #[test]
+    fn test_network_thread_roundtrip() {
+        let address = Address(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 12360)));
+
+        let server = ENET.host_builder().address(address).build::<()>().unwrap();
+        let client = ENET.host_builder().build::<()>().unwrap();
+
+        let (server_thread, _, server_events) = NetworkThread::spawn(server).unwrap();
+        let (client_thread, client, client_events) = NetworkThread::spawn(client).unwrap();
+
+        let peer = client.connect(&address, 1, 0).unwrap();
+        match client_events.recv_timeout(Duration::from_secs(1)).unwrap() {
+            OwnedEvent::Connect { peer: connected, .. } => assert_eq!(connected, peer),
+            other => panic!("unexpected event: {:?}", other),
+        }
+
+        client
+            .send(peer, 0, Packet::new(b"hello", PacketMode::ReliableSequenced).unwrap())
+            .unwrap();
+
+        let mut received = None;
+        while received.is_none() {
+            match server_events.recv_timeout(Duration::from_secs(1)).unwrap() {
+                OwnedEvent::Receive { packet, .. } => received = Some(packet),
+                OwnedEvent::Connect { .. } => (),
+                other => panic!("unexpected event: {:?}", other),
+            }
+        }
+        assert_eq!(received.unwrap().data(), b"hello");
+
+        client_thread.shutdown().unwrap();
+        server_thread.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_commands_applied_while_flooded() {
+        let address = localhost(12388);
+        let server = ENET.host_builder().address(address).build::<()>().unwrap();
+        let (server_thread, server, server_events) = NetworkThread::spawn(server).unwrap();
+
+        let mut client = ENET.host_builder().build::<()>().unwrap();
+        let peer = client.connect(&address, 1, 0).unwrap();
+        service_until(Duration::from_secs(1), || {
+            matches!(client.service(5).unwrap(), Some(Event::Connect(_))).then_some(())
+        })
+        .expect("connection timed out");
+
+        server
+            .broadcast(0, Packet::new(b"ping", PacketMode::ReliableSequenced).unwrap())
+            .unwrap();
+
+        // keep the server busy with events while waiting for the broadcast
+        let received = service_until(Duration::from_secs(2), || {
+            let mut p = client.peer_mut(peer).unwrap();
+            for _ in 0..50 {
+                p.send_packet(Packet::new(b"flood", PacketMode::UnreliableSequenced).unwrap(), 0)
+                    .unwrap();
+            }
+
+            match client.service(0).unwrap() {
+                Some(Event::Receive { ref packet, .. }) => Some(packet.data().to_vec()),
+                _ => None,
+            }
+        });
+        assert_eq!(received.as_deref(), Some(&b"ping"[..]));
+        assert!(server_events.try_iter().count() > 1);
+
+        server_thread.shutdown().unwrap();
+    }
+}
@@ -0,0 +1,75 @@
+use crate::InterceptContext;
+
+/// The prefix of every connectionless out-of-band (OOB) datagram, as used by server browsers to query game servers.
+///
+/// The prefix is followed by the name of a command, and optionally a separator (a space or a newline)
+/// and a payload, e.g. `\xFF\xFF\xFF\xFFgetinfo xxx`. ENet never sends datagrams starting with it.
+pub const OOB_PREFIX: [u8; 4] = [0xFF; 4];
+
+/// A handler for an OOB command, see `Host::register_oob_handler`.
+pub(crate) type OobHandler = Box<dyn FnMut(&mut InterceptContext<'_>, &[u8]) + Send>;
+
+/// Splits an OOB datagram into its command and payload, or returns `None` for any other datagram.
+pub(crate) fn parse(data: &[u8]) -> Option<(&str, &[u8])> {
+    let message = data.strip_prefix(&OOB_PREFIX)?;
+
+    let (command, payload) = match message.iter().position(|&b| b == b' ' || b == b'\n') {
+        Some(separator) => (&message[..separator], &message[separator + 1..]),
+        None => (message, &[][..]),
+    };
+
+    Some((std::str::from_utf8(command).ok()?, payload))
+}
+
+/// Builds an OOB datagram, separating `command` and `payload` by a newline like Quake-derived servers do.
+pub(crate) fn encode(command: &str, payload: &[u8]) -> Vec<u8> {
+    let mut datagram = Vec::with_capacity(OOB_PREFIX.len() + command.len() + 1 + payload.len());
+
+    datagram.extend_from_slice(&OOB_PREFIX);
+    datagram.extend_from_slice(command.as_bytes());
+    if !payload.is_empty() {
+        datagram.push(b'\n');
+        datagram.extend_from_slice(payload);
+    }
+
+    datagram
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+        time::Duration,
+    };
+
+    use super::{encode, parse};
+    use crate::{tests::ENET, Address};
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse(b"\xFF\xFF\xFF\xFFgetinfo xxx"), Some(("getinfo", &b"xxx"[..])));
+        assert_eq!(parse(b"\xFF\xFF\xFF\xFFgetstatus"), Some(("getstatus", &b""[..])));
+        assert_eq!(parse(&encode("infoResponse", b"\\hostname\\test")), Some(("infoResponse", &b"\\hostname\\test"[..])));
+        assert_eq!(parse(b"\xFF\xFF\xFFgetinfo"), None);
+    }
+
+    #[test]
+    fn test_oob_handler_replies() {
+        let address = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 12371));
+        let mut host = ENET.host_builder().address(Address(address)).build::<()>().unwrap();
+
+        host.register_oob_handler("getinfo", |context, challenge| {
+            context.reply_oob("infoResponse", challenge).unwrap();
+        });
+
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+        socket.send_to(b"\xFF\xFF\xFF\xFFgetinfo 1234", address).unwrap();
+        assert!(host.service(100).unwrap().is_none());
+
+        let mut buffer = [0; 32];
+        let (len, _) = socket.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"\xFF\xFF\xFF\xFFinfoResponse\n1234");
+    }
+}
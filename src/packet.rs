@@ -1,16 +1,29 @@
+use std::{io, ptr, slice};
+
+use bitflags::bitflags;
+
 use citizen_enet_sys::{
-    enet_packet_create, enet_packet_destroy, ENetPacket, _ENetPacketFlag_ENET_PACKET_FLAG_RELIABLE,
-    _ENetPacketFlag_ENET_PACKET_FLAG_UNSEQUENCED,
+    enet_packet_create, enet_packet_destroy, enet_packet_resize, ENetPacket, _ENetPacketFlag_ENET_PACKET_FLAG_NO_ALLOCATE,
+    _ENetPacketFlag_ENET_PACKET_FLAG_RELIABLE, _ENetPacketFlag_ENET_PACKET_FLAG_SENT,
+    _ENetPacketFlag_ENET_PACKET_FLAG_UNRELIABLE_FRAGMENT, _ENetPacketFlag_ENET_PACKET_FLAG_UNSEQUENCED,
 };
 
-use crate::Error;
+use crate::{Error, PeerId};
 
 /// A packet that can be sent or retrieved on an ENet-connection.
 #[derive(Debug)]
 pub struct Packet {
     inner: *mut ENetPacket,
+    // size of the data allocated by ENet, which stays allocated when the packet shrinks
+    capacity: usize,
+    // sender and channel of a received packet, reported by decode errors (the only reader, hence the gating)
+    #[cfg(feature = "serde")]
+    pub(crate) origin: Option<(PeerId, u8)>,
 }
 
+// A `Packet` exclusively owns its `ENetPacket`, which is not tied to any `Host`.
+unsafe impl Send for Packet {}
+
 #[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
 /// Mode that can be set when transmitting a packet.
 ///
@@ -22,6 +35,31 @@ pub enum PacketMode {
     UnreliableUnsequenced,
     /// The packet will be sent reliably and sequenced with other reliable packets.
     ReliableSequenced,
+    /// The packet will be sent unreliably but sequenced, and if it exceeds the MTU, its fragments are sent
+    /// unreliably as well. Without this, ENet sends fragments of unreliable packets reliably.
+    UnreliableFragment,
+}
+
+bitflags! {
+    /// The ENet flags of a packet, as returned by `Packet::flags`.
+    pub struct PacketFlags: u32 {
+        /// The packet is sent reliably.
+        const RELIABLE = _ENetPacketFlag_ENET_PACKET_FLAG_RELIABLE;
+        /// The packet is sent unsequenced.
+        const UNSEQUENCED = _ENetPacketFlag_ENET_PACKET_FLAG_UNSEQUENCED;
+        /// The data of the packet is not owned by ENet, see `Packet::from_vec`.
+        const NO_ALLOCATE = _ENetPacketFlag_ENET_PACKET_FLAG_NO_ALLOCATE;
+        /// The fragments of the packet are sent unreliably.
+        const UNRELIABLE_FRAGMENT = _ENetPacketFlag_ENET_PACKET_FLAG_UNRELIABLE_FRAGMENT;
+        /// The packet has been sent by all peers it was queued on.
+        const SENT = _ENetPacketFlag_ENET_PACKET_FLAG_SENT;
+    }
+}
+
+impl From<PacketMode> for PacketFlags {
+    fn from(mode: PacketMode) -> PacketFlags {
+        PacketFlags::from_bits_truncate(mode.to_sys_flags())
+    }
 }
 
 impl PacketMode {
@@ -31,6 +69,7 @@ impl PacketMode {
             PacketMode::UnreliableSequenced => false,
             PacketMode::UnreliableUnsequenced => false,
             PacketMode::ReliableSequenced => true,
+            PacketMode::UnreliableFragment => false,
         }
     }
 
@@ -40,6 +79,7 @@ impl PacketMode {
             PacketMode::UnreliableSequenced => true,
             PacketMode::UnreliableUnsequenced => false,
             PacketMode::ReliableSequenced => true,
+            PacketMode::UnreliableFragment => true,
         }
     }
 
@@ -48,20 +88,63 @@ impl PacketMode {
             PacketMode::UnreliableSequenced => 0,
             PacketMode::UnreliableUnsequenced => _ENetPacketFlag_ENET_PACKET_FLAG_UNSEQUENCED as u32,
             PacketMode::ReliableSequenced => _ENetPacketFlag_ENET_PACKET_FLAG_RELIABLE as u32,
+            PacketMode::UnreliableFragment => _ENetPacketFlag_ENET_PACKET_FLAG_UNRELIABLE_FRAGMENT,
         }
     }
 
     fn from_sys_flags(flags: u32) -> Self {
-        if (flags & _ENetPacketFlag_ENET_PACKET_FLAG_UNSEQUENCED as u32) != 0 {
-            PacketMode::UnreliableUnsequenced
-        } else if (flags & _ENetPacketFlag_ENET_PACKET_FLAG_RELIABLE as u32) != 0 {
+        // ENet sends reliable packets reliably, and fragmented packets sequenced, regardless of the other flags
+        if (flags & _ENetPacketFlag_ENET_PACKET_FLAG_RELIABLE as u32) != 0 {
             PacketMode::ReliableSequenced
+        } else if (flags & _ENetPacketFlag_ENET_PACKET_FLAG_UNRELIABLE_FRAGMENT) != 0 {
+            PacketMode::UnreliableFragment
+        } else if (flags & _ENetPacketFlag_ENET_PACKET_FLAG_UNSEQUENCED as u32) != 0 {
+            PacketMode::UnreliableUnsequenced
         } else {
             PacketMode::UnreliableSequenced
         }
     }
 }
 
+/// A Rust-owned buffer backing a `Packet` that was created without copying.
+///
+/// Stored in the `userData` of the `ENetPacket` and released by `free_buffer`
+/// once ENet destroys the packet, i.e. when it has been sent to all recipients.
+enum PacketBuffer {
+    Vec(Vec<u8>),
+    #[cfg(feature = "bytes")]
+    Bytes(bytes::Bytes),
+}
+
+impl PacketBuffer {
+    fn as_slice(&self) -> &[u8] {
+        match self {
+            PacketBuffer::Vec(data) => data,
+            #[cfg(feature = "bytes")]
+            PacketBuffer::Bytes(data) => data,
+        }
+    }
+
+    /// Returns the buffer as a `Vec`, copying it first if it is a possibly shared `Bytes`.
+    fn make_mut(&mut self) -> &mut Vec<u8> {
+        #[cfg(feature = "bytes")]
+        if let PacketBuffer::Bytes(data) = self {
+            *self = PacketBuffer::Vec(data.to_vec());
+        }
+
+        match self {
+            PacketBuffer::Vec(data) => data,
+            #[cfg(feature = "bytes")]
+            PacketBuffer::Bytes(_) => unreachable!("converted above"),
+        }
+    }
+}
+
+unsafe extern "C" fn free_buffer(packet: *mut ENetPacket) {
+    drop(Box::from_raw((*packet).userData as *mut PacketBuffer));
+    (*packet).userData = ptr::null_mut();
+}
+
 impl Packet {
     /// Creates a new Packet with optional reliability settings.
     pub fn new(data: &[u8], mode: PacketMode) -> Result<Packet, Error> {
@@ -76,8 +159,103 @@ impl Packet {
         Ok(Packet::from_sys_packet(res))
     }
 
+    /// Creates a new, empty Packet with room for `capacity` bytes, to be filled through `io::Write` or `resize`.
+    pub fn with_capacity(capacity: usize, mode: PacketMode) -> Result<Packet, Error> {
+        let res = unsafe { enet_packet_create(ptr::null(), capacity, mode.to_sys_flags()) };
+
+        if res.is_null() {
+            return Err(Error(0));
+        }
+
+        unsafe {
+            (*res).dataLength = 0;
+        }
+
+        Ok(Packet {
+            inner: res,
+            capacity,
+            #[cfg(feature = "serde")]
+            origin: None,
+        })
+    }
+
+    /// Creates a new Packet from `data` without copying it.
+    ///
+    /// `data` is kept alive until ENet is done with the packet, which may be after
+    /// the `Packet` itself has been dropped.
+    pub fn from_vec(data: Vec<u8>, mode: PacketMode) -> Result<Packet, Error> {
+        Packet::from_buffer(PacketBuffer::Vec(data), mode)
+    }
+
+    /// Creates a new Packet from `data` without copying it. Requires the `bytes` feature.
+    ///
+    /// `data` is kept alive until ENet is done with the packet, which may be after
+    /// the `Packet` itself has been dropped.
+    #[cfg(feature = "bytes")]
+    pub fn from_bytes(data: bytes::Bytes, mode: PacketMode) -> Result<Packet, Error> {
+        Packet::from_buffer(PacketBuffer::Bytes(data), mode)
+    }
+
+    fn from_buffer(buffer: PacketBuffer, mode: PacketMode) -> Result<Packet, Error> {
+        let buffer = Box::new(buffer);
+        let data = buffer.as_slice();
+
+        let res = unsafe {
+            enet_packet_create(
+                data.as_ptr() as *const _,
+                data.len(),
+                mode.to_sys_flags() | _ENetPacketFlag_ENET_PACKET_FLAG_NO_ALLOCATE,
+            )
+        };
+
+        if res.is_null() {
+            return Err(Error(0));
+        }
+
+        unsafe {
+            (*res).userData = Box::into_raw(buffer) as *mut _;
+            (*res).freeCallback = Some(free_buffer);
+        }
+
+        Ok(Packet::from_sys_packet(res))
+    }
+
     pub(crate) fn from_sys_packet(inner: *mut ENetPacket) -> Packet {
-        Packet { inner }
+        Packet {
+            inner,
+            capacity: unsafe { (*inner).dataLength },
+            #[cfg(feature = "serde")]
+            origin: None,
+        }
+    }
+
+    /// Like `from_sys_packet`, for a packet received from `sender` on channel `channel_id`.
+    pub(crate) fn received(inner: *mut ENetPacket, sender: PeerId, channel_id: u8) -> Packet {
+        #[cfg(feature = "serde")]
+        {
+            let mut packet = Packet::from_sys_packet(inner);
+            packet.origin = Some((sender, channel_id));
+            packet
+        }
+
+        #[cfg(not(feature = "serde"))]
+        {
+            let _ = (sender, channel_id);
+            Packet::from_sys_packet(inner)
+        }
+    }
+
+    /// Returns the Rust-owned buffer of a packet created by `from_vec` or `from_bytes`.
+    fn buffer_mut(&mut self) -> Option<&mut PacketBuffer> {
+        unsafe {
+            let user_data = (*self.inner).userData as *mut PacketBuffer;
+
+            if (*self.inner).flags & _ENetPacketFlag_ENET_PACKET_FLAG_NO_ALLOCATE != 0 && !user_data.is_null() {
+                Some(&mut *user_data)
+            } else {
+                None
+            }
+        }
     }
 
     /// Does NOT run this `Packet`'s destructor.
@@ -89,12 +267,110 @@ impl Packet {
 
     /// Returns a reference to the bytes inside this packet.
     pub fn data<'a>(&'a self) -> &'a [u8] {
-        unsafe { std::slice::from_raw_parts((*self.inner).data, (*self.inner).dataLength) }
+        unsafe {
+            // ENet does not allocate any data for empty packets
+            if (*self.inner).data.is_null() {
+                return &[];
+            }
+
+            slice::from_raw_parts((*self.inner).data, (*self.inner).dataLength)
+        }
+    }
+
+    /// Returns a mutable reference to the bytes inside this packet.
+    ///
+    /// A packet created by `from_bytes` is copied first, as its buffer may be shared.
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        let inner = self.inner;
+
+        if let Some(data) = self.buffer_mut().map(PacketBuffer::make_mut) {
+            unsafe {
+                (*inner).data = data.as_mut_ptr();
+            }
+        }
+
+        unsafe {
+            if (*inner).data.is_null() {
+                return &mut [];
+            }
+
+            slice::from_raw_parts_mut((*inner).data, (*inner).dataLength)
+        }
+    }
+
+    /// Resizes this packet to `new_len` bytes, filling new bytes with zeroes.
+    ///
+    /// Shrinking keeps the allocation, so the packet can grow back without reallocating.
+    /// A packet created by `from_bytes` is copied first, as its buffer may be shared.
+    pub fn resize(&mut self, new_len: usize) -> Result<(), Error> {
+        let inner = self.inner;
+        let old_len = unsafe { (*inner).dataLength };
+
+        // ENet does not reallocate buffers it did not allocate itself, so these are resized here
+        if let Some(data) = self.buffer_mut().map(PacketBuffer::make_mut) {
+            data.resize(new_len, 0);
+
+            unsafe {
+                (*inner).data = data.as_mut_ptr();
+                (*inner).dataLength = new_len;
+            }
+
+            return Ok(());
+        }
+
+        if new_len > self.capacity {
+            // grow at least exponentially, so writing in small pieces does not reallocate every time
+            let capacity = new_len.max(self.capacity * 2);
+
+            let res = unsafe { enet_packet_resize(inner, capacity) };
+            if res < 0 {
+                return Err(Error(res));
+            }
+
+            self.capacity = capacity;
+        }
+
+        unsafe {
+            (*inner).dataLength = new_len;
+        }
+
+        if new_len > old_len {
+            self.data_mut()[old_len..].fill(0);
+        }
+
+        Ok(())
     }
 
     pub fn mode(&self) -> PacketMode {
         PacketMode::from_sys_flags(unsafe { (*self.inner).flags })
     }
+
+    /// Returns all ENet flags of this packet.
+    ///
+    /// Received packets only carry the flags describing how they were delivered: `RELIABLE`, `UNSEQUENCED` or
+    /// `UNRELIABLE_FRAGMENT`, or none for unreliable sequenced packets. `NO_ALLOCATE` and `SENT` are never set,
+    /// and an unreliable packet that exceeded the MTU without `UNRELIABLE_FRAGMENT` arrives as `RELIABLE`,
+    /// since ENet sent its fragments reliably.
+    pub fn flags(&self) -> PacketFlags {
+        PacketFlags::from_bits_truncate(unsafe { (*self.inner).flags })
+    }
+}
+
+/// Appends the written bytes to the packet, growing it as necessary.
+impl io::Write for Packet {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.data().len();
+
+        self.resize(len + buf.len())
+            .map_err(|err| io::Error::new(io::ErrorKind::OutOfMemory, err.to_string()))?;
+        self.data_mut()[len..].copy_from_slice(buf);
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 }
 
 impl Drop for Packet {
@@ -104,3 +380,93 @@ impl Drop for Packet {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, io::Write, time::Duration};
+
+    use super::{Packet, PacketFlags, PacketMode};
+    use crate::{
+        tests::{connect_pair, service_until},
+        OwnedEvent,
+    };
+
+    #[test]
+    fn test_from_vec_does_not_copy() {
+        let data = b"zero-copy".to_vec();
+        let ptr = data.as_ptr();
+
+        let packet = Packet::from_vec(data, PacketMode::ReliableSequenced).unwrap();
+
+        assert_eq!(packet.data().as_ptr(), ptr);
+        assert_eq!(packet.data(), b"zero-copy");
+        assert_eq!(packet.mode(), PacketMode::ReliableSequenced);
+        assert_eq!(packet.flags(), PacketFlags::RELIABLE | PacketFlags::NO_ALLOCATE);
+    }
+
+    #[test]
+    fn test_unreliable_fragment_mode() {
+        let packet = Packet::new(&[0; 4096], PacketMode::UnreliableFragment).unwrap();
+
+        assert_eq!(packet.mode(), PacketMode::UnreliableFragment);
+        assert_eq!(packet.flags(), PacketFlags::UNRELIABLE_FRAGMENT);
+        assert!(!packet.mode().is_reliable());
+    }
+
+    #[test]
+    fn test_write_and_resize() {
+        let mut packet = Packet::with_capacity(4, PacketMode::UnreliableSequenced).unwrap();
+        assert_eq!(packet.data(), b"");
+
+        write!(packet, "hello {}", 42).unwrap();
+        assert_eq!(packet.data(), b"hello 42");
+
+        packet.resize(5).unwrap();
+        packet.data_mut()[0] = b'j';
+        packet.resize(7).unwrap();
+        assert_eq!(packet.data(), b"jello\0\0");
+
+        let mut packet = Packet::from_vec(b"abc".to_vec(), PacketMode::UnreliableSequenced).unwrap();
+        packet.write_all(b"def").unwrap();
+        assert_eq!(packet.data(), b"abcdef");
+    }
+
+    #[test]
+    fn test_received_flags() {
+        let (mut server, mut client, peer) = connect_pair(12386);
+
+        // (tag, length, mode, flags on arrival); the packets are told apart by their first byte
+        let sent = [
+            (0, 8, PacketMode::ReliableSequenced, PacketFlags::RELIABLE),
+            (1, 8, PacketMode::UnreliableSequenced, PacketFlags::empty()),
+            (2, 8, PacketMode::UnreliableUnsequenced, PacketFlags::UNSEQUENCED),
+            (3, 8, PacketMode::UnreliableFragment, PacketFlags::empty()),
+            (4, 4096, PacketMode::UnreliableSequenced, PacketFlags::RELIABLE),
+            (5, 4096, PacketMode::UnreliableFragment, PacketFlags::UNRELIABLE_FRAGMENT),
+        ];
+
+        let mut p = client.peer_mut(peer).unwrap();
+        for &(tag, len, mode, _) in &sent {
+            let mut data = vec![0; len];
+            data[0] = tag;
+            p.send_packet(Packet::from_vec(data, mode).unwrap(), 1).unwrap();
+        }
+        client.flush();
+
+        let mut received = HashMap::new();
+        service_until(Duration::from_secs(1), || {
+            client.service(0).unwrap();
+            if let Some(OwnedEvent::Receive { packet, .. }) = server.service(5).unwrap().map(OwnedEvent::from) {
+                received.insert(packet.data()[0], packet);
+            }
+            Some(()).filter(|_| received.len() == sent.len())
+        })
+        .expect("receiving timed out");
+
+        for (tag, len, _, flags) in sent {
+            let packet = &received[&tag];
+            assert_eq!(packet.data().len(), len);
+            assert_eq!(packet.flags(), flags, "flags of packet {}", tag);
+        }
+    }
+}
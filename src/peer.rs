@@ -3,7 +3,7 @@ use std::time::Duration;
 
 use citizen_enet_sys::{
     enet_peer_disconnect, enet_peer_disconnect_later, enet_peer_disconnect_now, enet_peer_receive,
-    enet_peer_reset, enet_peer_send, enet_peer_throttle_configure, enet_peer_timeout, ENetPeer,
+    enet_peer_reset, enet_peer_throttle_configure, enet_peer_timeout, ENetPeer,
     _ENetPeerState,
     _ENetPeerState_ENET_PEER_STATE_DISCONNECTED,
     _ENetPeerState_ENET_PEER_STATE_CONNECTING,
@@ -17,13 +17,16 @@ use citizen_enet_sys::{
     _ENetPeerState_ENET_PEER_STATE_ZOMBIE,
 };
 
-use citizen_enet_sys::ENET_PEER_PACKET_THROTTLE_SCALE;
+use citizen_enet_sys::{enet_packet_destroy, ENET_PEER_PACKET_LOSS_SCALE, ENET_PEER_PACKET_THROTTLE_SCALE};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
 /// When the throttle has a value of ENET_PEER_PACKET_THROTTLE_SCALE,
 /// no unreliable packets are dropped by ENet, and so 100% of all unreliable packets will be sent. 
 pub static PACKET_THROTTLE_SCALE: u32 = ENET_PEER_PACKET_THROTTLE_SCALE as u32;
 
-use crate::{Address, Error, Packet};
+use crate::{host::Layers, Address, Error, Packet, PeerError};
 
 /// This struct represents an endpoint in an ENet-connection.
 ///
@@ -35,10 +38,44 @@ use crate::{Address, Error, Packet};
 #[derive(Clone, Debug)]
 pub struct Peer<'a, T: 'a> {
     inner: *mut ENetPeer,
+    connect_id: u32,
+    // the authentication and encryption of the `Host`, which outlives the `Peer`
+    layers: *mut Layers,
 
     _data: PhantomData<&'a mut T>,
 }
 
+/// A read-only view of a `Peer`, as returned by `Host::peer`.
+///
+/// Offers the methods of `Peer` that only inspect it, so it can be obtained from a shared borrow of the `Host`.
+#[derive(Debug)]
+pub struct PeerRef<'a, T: 'a> {
+    peer: Peer<'a, T>,
+}
+
+/// A stable handle to a `Peer`, which can be stored and used across calls to `Host::service`.
+///
+/// A `PeerId` consists of the index of the peer's slot in its `Host` and the connect ID of the connection
+/// occupying that slot. ENet reuses slots for new connections, so a `PeerId` that refers to an older connection
+/// is detected as stale by `Host::peer` and `Host::peer_mut`, which will return `None` for it.
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub struct PeerId {
+    index: usize,
+    connect_id: u32,
+}
+
+impl PeerId {
+    /// Returns the index of the peer's slot in its `Host`.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Returns the ENet connect ID of the connection this `PeerId` refers to.
+    pub fn connect_id(&self) -> u32 {
+        self.connect_id
+    }
+}
+
 /// A packet received directly from a `Peer`.
 ///
 /// Contains the received packet as well as the channel on which it was received.
@@ -52,6 +89,43 @@ pub struct PeerPacket<'b, 'a, T: 'a> {
     _priv_guard: PhantomData<&'b Peer<'a, T>>,
 }
 
+/// A snapshot of the connection statistics ENet tracks for a `Peer`, as returned by `Peer::stats`.
+///
+/// Timestamps are in milliseconds of ENet's clock, comparable to `Host::service_time`.
+/// Serializable with the `serde` feature.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PeerStats {
+    /// Mean round trip time between sending a reliable packet and receiving its acknowledgement.
+    pub round_trip_time: Duration,
+    /// Variance of the round trip time.
+    pub round_trip_time_variance: Duration,
+    /// Mean packet loss of reliable packets, as a fraction between 0.0 and 1.0.
+    pub packet_loss: f32,
+    /// Variance of the packet loss, as a fraction between 0.0 and 1.0.
+    pub packet_loss_variance: f32,
+    /// Current throttle value for unreliable packets, out of `PEER_PACKET_THROTTLE_SCALE`.
+    pub packet_throttle: u32,
+    /// Number of reliable packets sent in the current packet loss interval.
+    pub packets_sent: u32,
+    /// Number of reliable packets lost in the current packet loss interval.
+    pub packets_lost: u32,
+    /// Amount of reliable data sent but not yet acknowledged, in bytes.
+    pub reliable_data_in_transit: u32,
+    /// Time at which data was last sent to the peer.
+    pub last_send_time: u32,
+    /// Time at which data was last received from the peer.
+    pub last_receive_time: u32,
+    /// Size of the window for reliable data in transit, in bytes.
+    pub window_size: u32,
+    /// MTU of the connection, in bytes.
+    pub mtu: u32,
+    /// Downstream bandwidth of the peer in bytes/second.
+    pub incoming_bandwidth: u32,
+    /// Upstream bandwidth of the peer in bytes/second.
+    pub outgoing_bandwidth: u32,
+}
+
 /// Describes the state a `Peer` is in.
 ///
 /// The states should be self-explanatory, ENet doesn't explain them more either.
@@ -71,7 +145,7 @@ pub enum PeerState {
 }
 
 impl PeerState {
-    fn from_sys_state(citizen_enet_sys_state: _ENetPeerState) -> PeerState {
+    pub(crate) fn from_sys_state(citizen_enet_sys_state: _ENetPeerState) -> PeerState {
         #[allow(non_upper_case_globals)]
         match citizen_enet_sys_state {
             _ENetPeerState_ENET_PEER_STATE_DISCONNECTED => PeerState::Disconnected,
@@ -90,13 +164,31 @@ impl PeerState {
 }
 
 impl<'a, T> Peer<'a, T> {
-    pub(crate) fn new(inner: *mut ENetPeer) -> Self {
+    pub(crate) fn new(inner: *mut ENetPeer, layers: *mut Layers) -> Self {
+        Self::with_connect_id(inner, unsafe { (*inner).connectID }, layers)
+    }
+
+    /// Creates a `Peer` whose `id()` refers to `connect_id`, rather than the connect ID currently stored by ENet.
+    ///
+    /// ENet resets a peer (including its connect ID) before reporting its disconnection, so `Event::Disconnect`
+    /// uses this to keep the `PeerId` of the connection that just ended.
+    pub(crate) fn with_connect_id(inner: *mut ENetPeer, connect_id: u32, layers: *mut Layers) -> Self {
         Self {
             inner,
+            connect_id,
+            layers,
             _data: PhantomData,
         }
     }
 
+    /// Returns a `PeerId` for this `Peer`, which can be stored and later resolved using `Host::peer`/`Host::peer_mut`.
+    pub fn id(&self) -> PeerId {
+        PeerId {
+            index: unsafe { (*self.inner).incomingPeerID } as usize,
+            connect_id: self.connect_id,
+        }
+    }
+
     /// Returns the address of this `Peer`.
     pub fn address(&self) -> Address {
         Address::from_enet_address(&unsafe { (*self.inner).address })
@@ -172,6 +264,30 @@ impl<'a, T> Peer<'a, T> {
         Duration::from_millis(unsafe { (*self.inner).roundTripTime } as u64)
     }
 
+    /// Returns a snapshot of the connection statistics of this `Peer`.
+    pub fn stats(&self) -> PeerStats {
+        const LOSS_SCALE: f32 = ENET_PEER_PACKET_LOSS_SCALE as f32;
+
+        let peer = unsafe { &*self.inner };
+
+        PeerStats {
+            round_trip_time: Duration::from_millis(peer.roundTripTime as u64),
+            round_trip_time_variance: Duration::from_millis(peer.roundTripTimeVariance as u64),
+            packet_loss: peer.packetLoss as f32 / LOSS_SCALE,
+            packet_loss_variance: peer.packetLossVariance as f32 / LOSS_SCALE,
+            packet_throttle: peer.packetThrottle,
+            packets_sent: peer.packetsSent,
+            packets_lost: peer.packetsLost,
+            reliable_data_in_transit: peer.reliableDataInTransit,
+            last_send_time: peer.lastSendTime,
+            last_receive_time: peer.lastReceiveTime,
+            window_size: peer.windowSize,
+            mtu: peer.mtu,
+            incoming_bandwidth: peer.incomingBandwidth,
+            outgoing_bandwidth: peer.outgoingBandwidth,
+        }
+    }
+
     /// Forcefully disconnects this `Peer`.
     ///
     /// The foreign host represented by the peer is not notified of the disconnection and will timeout on its connection to the local host.
@@ -202,13 +318,30 @@ impl<'a, T> Peer<'a, T> {
     /// Queues a packet to be sent.
     ///
     /// Actual sending will happen during `Host::service`.
-    pub fn send_packet(&mut self, packet: Packet, channel_id: u8) -> Result<(), Error> {
-        let res = unsafe { enet_peer_send(self.inner, channel_id, packet.into_inner()) };
+    ///
+    /// With `Host::set_authentication`, this fails with `PeerError::Unauthenticated` if the peer did not
+    /// authenticate yet.
+    /// With `Host::set_encryption`, the packet is encrypted, which fails if the peer's handshake did not complete.
+    pub fn send_packet(&mut self, packet: Packet, channel_id: u8) -> Result<(), PeerError> {
+        #[cfg(feature = "auth")]
+        if unsafe { (*self.layers).is_pending(self.inner) } {
+            return Err(PeerError::Unauthenticated(self.id()));
+        }
+
+        let res = unsafe {
+            let packet = packet.into_inner();
+            let res = (*self.layers).send(self.inner, channel_id, packet);
+            // an encrypted packet is sent as a copy, and a packet ENet did not take is not referenced either
+            if (*packet).referenceCount == 0 {
+                enet_packet_destroy(packet);
+            }
+            res
+        };
 
         match res {
             r if r > 0 => panic!("unexpected res: {}", r),
             0 => Ok(()),
-            r if r < 0 => Err(Error(r)),
+            r if r < 0 => Err(PeerError::Enet(Error(r))),
             _ => panic!("unreachable"),
         }
     }
@@ -246,16 +379,125 @@ impl<'a, T> Peer<'a, T> {
     pub fn receive<'b>(&'b mut self) -> Option<PeerPacket<'b, 'a, T>> {
         let mut channel_id = 0u8;
 
-        let res = unsafe { enet_peer_receive(self.inner, &mut channel_id as *mut _) };
+        let res = loop {
+            let res = unsafe { enet_peer_receive(self.inner, &mut channel_id as *mut _) };
 
-        if res.is_null() {
-            return None;
-        }
+            if res.is_null() {
+                return None;
+            }
+
+            #[cfg(feature = "encryption")]
+            let res = unsafe { (*self.layers).receive(self.inner, channel_id, res) };
+
+            // a null packet was dropped by the encryption, so try the next one
+            if !res.is_null() {
+                break res;
+            }
+        };
 
         Some(PeerPacket {
-            packet: Packet::from_sys_packet(res),
+            packet: Packet::received(res, self.id(), channel_id),
             channel_id,
             _priv_guard: PhantomData,
         })
     }
 }
+
+impl<'a, T> PeerRef<'a, T> {
+    pub(crate) fn new(inner: *mut ENetPeer, layers: *mut Layers) -> Self {
+        Self {
+            peer: Peer::new(inner, layers),
+        }
+    }
+
+    /// See `Peer::id`.
+    pub fn id(&self) -> PeerId {
+        self.peer.id()
+    }
+
+    /// See `Peer::address`.
+    pub fn address(&self) -> Address {
+        self.peer.address()
+    }
+
+    /// See `Peer::channel_count`.
+    pub fn channel_count(&self) -> usize {
+        self.peer.channel_count()
+    }
+
+    /// See `Peer::event_data`.
+    pub fn event_data(&self) -> u32 {
+        self.peer.event_data()
+    }
+
+    /// See `Peer::data`.
+    pub fn data(&self) -> Option<&T> {
+        self.peer.data()
+    }
+
+    /// See `Peer::incoming_bandwidth`.
+    pub fn incoming_bandwidth(&self) -> u32 {
+        self.peer.incoming_bandwidth()
+    }
+
+    /// See `Peer::outgoing_bandwidth`.
+    pub fn outgoing_bandwidth(&self) -> u32 {
+        self.peer.outgoing_bandwidth()
+    }
+
+    /// See `Peer::mean_rtt`.
+    pub fn mean_rtt(&self) -> Duration {
+        self.peer.mean_rtt()
+    }
+
+    /// See `Peer::stats`.
+    pub fn stats(&self) -> PeerStats {
+        self.peer.stats()
+    }
+
+    /// See `Peer::state`.
+    pub fn state(&self) -> PeerState {
+        self.peer.state()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        tests::{connect_pair, service_until},
+        Event, Packet, PacketMode,
+    };
+
+    #[test]
+    fn test_stats_after_exchange() {
+        let (mut server, mut client, peer) = connect_pair(12387);
+        let initial = client.peer(peer).unwrap().stats();
+
+        for i in 0..20u8 {
+            let packet = Packet::new(&[i; 32], PacketMode::ReliableSequenced).unwrap();
+            client.peer_mut(peer).unwrap().send_packet(packet, 0).unwrap();
+            client.flush();
+
+            // wait for the packet and its acknowledgement, so every send yields a round trip sample
+            let mut received = false;
+            service_until(Duration::from_secs(1), || {
+                client.service(0).unwrap();
+                received |= matches!(server.service(1).unwrap(), Some(Event::Receive { .. }));
+                let in_transit = client.peer(peer).unwrap().stats().reliable_data_in_transit;
+                Some(()).filter(|_| received && in_transit == 0)
+            })
+            .expect("exchange timed out");
+        }
+
+        let stats = client.peer(peer).unwrap().stats();
+        // ENet starts out assuming a round trip time of 500ms, which loopback samples quickly bring down
+        assert!(stats.round_trip_time < Duration::from_millis(500), "{:?}", stats);
+        assert!(stats.packets_sent >= initial.packets_sent + 20, "{:?}", stats);
+        assert_eq!(stats.packets_lost, 0);
+        assert_eq!(stats.packet_loss, 0.0);
+        assert_eq!(stats.reliable_data_in_transit, 0);
+        assert!(stats.last_receive_time > 0 && stats.mtu > 0);
+    }
+}
@@ -0,0 +1,798 @@
+//! A dissector and encoder for ENet's wire protocol, e.g. to inspect the datagrams seen by
+//! `Host::set_intercept`, or to build exact datagrams in tests and send them with `Socket::send_data`.
+//!
+//! A datagram consists of a `Header`, an optional checksum (if the hosts use a `Checksum`) and a list of
+//! `Command`s. All fields are in network byte order, except for the checksum, which ENet writes in the byte order
+//! of the sending machine; the data of packets is borrowed from the parsed datagram.
+//!
+//! ```
+//! use enet::protocol::{Command, CommandBody, Datagram, Header};
+//!
+//! let datagram = Datagram {
+//!     header: Header { peer_id: 0, session_id: 1, compressed: false, sent_time: Some(1234) },
+//!     checksum: None,
+//!     commands: vec![Command::new(0, 1, true, CommandBody::SendReliable { data: b"hello" })],
+//! };
+//!
+//! let bytes = datagram.encode();
+//! assert_eq!(Datagram::parse(&bytes).unwrap(), datagram);
+//! ```
+
+use std::convert::TryFrom;
+
+use citizen_enet_sys::ENET_PROTOCOL_MAXIMUM_PEER_ID;
+
+/// The peer ID of datagrams sent before the receiving peer was assigned one, i.e. of connection requests.
+pub const UNASSIGNED_PEER_ID: u16 = ENET_PROTOCOL_MAXIMUM_PEER_ID as u16;
+
+const HEADER_FLAG_COMPRESSED: u16 = 1 << 14;
+const HEADER_FLAG_SENT_TIME: u16 = 1 << 15;
+const HEADER_SESSION_MASK: u16 = 3 << 12;
+const HEADER_SESSION_SHIFT: u16 = 12;
+
+const COMMAND_MASK: u8 = 0x0F;
+const COMMAND_FLAG_ACKNOWLEDGE: u8 = 1 << 7;
+const COMMAND_FLAG_UNSEQUENCED: u8 = 1 << 6;
+
+const COMMAND_ACKNOWLEDGE: u8 = 1;
+const COMMAND_CONNECT: u8 = 2;
+const COMMAND_VERIFY_CONNECT: u8 = 3;
+const COMMAND_DISCONNECT: u8 = 4;
+const COMMAND_PING: u8 = 5;
+const COMMAND_SEND_RELIABLE: u8 = 6;
+const COMMAND_SEND_UNRELIABLE: u8 = 7;
+const COMMAND_SEND_FRAGMENT: u8 = 8;
+const COMMAND_SEND_UNSEQUENCED: u8 = 9;
+const COMMAND_BANDWIDTH_LIMIT: u8 = 10;
+const COMMAND_THROTTLE_CONFIGURE: u8 = 11;
+const COMMAND_SEND_UNRELIABLE_FRAGMENT: u8 = 12;
+
+/// An error that can occur when parsing a datagram.
+#[derive(Fail, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// The datagram ends in the middle of its header or of a command.
+    #[fail(display = "datagram is truncated")]
+    Truncated,
+    /// The datagram contains a command that ENet does not know, with the contained command number.
+    #[fail(display = "unknown command {}", _0)]
+    UnknownCommand(u8),
+    /// The commands of the datagram are compressed by the `Compression` of the sending host,
+    /// so only its header can be parsed.
+    #[fail(display = "datagram is compressed")]
+    Compressed,
+}
+
+/// The header of every ENet datagram.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Header {
+    /// The ID the receiving host assigned to the sending peer (12 bits), or `UNASSIGNED_PEER_ID`.
+    pub peer_id: u16,
+    /// The session ID of the connection (2 bits), which tells datagrams of reused peer slots apart.
+    pub session_id: u8,
+    /// Whether the commands are compressed.
+    pub compressed: bool,
+    /// The time the datagram was sent at, in milliseconds of the sender's clock (truncated to 16 bits).
+    /// Only sent if the datagram contains a command that has to be acknowledged.
+    pub sent_time: Option<u16>,
+}
+
+impl Header {
+    /// Parses the header at the start of `data`, returning it and its length.
+    pub fn parse(data: &[u8]) -> Result<(Header, usize), ParseError> {
+        let mut reader = Reader(data);
+        let peer_id = reader.u16()?;
+
+        let sent_time = if peer_id & HEADER_FLAG_SENT_TIME != 0 {
+            Some(reader.u16()?)
+        } else {
+            None
+        };
+
+        let header = Header {
+            peer_id: peer_id & UNASSIGNED_PEER_ID,
+            session_id: ((peer_id & HEADER_SESSION_MASK) >> HEADER_SESSION_SHIFT) as u8,
+            compressed: peer_id & HEADER_FLAG_COMPRESSED != 0,
+            sent_time,
+        };
+
+        Ok((header, data.len() - reader.0.len()))
+    }
+
+    /// Appends the encoded header to `out`.
+    pub fn encode_into(&self, out: &mut Vec<u8>) {
+        let mut peer_id = (self.peer_id & UNASSIGNED_PEER_ID)
+            | ((u16::from(self.session_id) << HEADER_SESSION_SHIFT) & HEADER_SESSION_MASK);
+        if self.compressed {
+            peer_id |= HEADER_FLAG_COMPRESSED;
+        }
+        if self.sent_time.is_some() {
+            peer_id |= HEADER_FLAG_SENT_TIME;
+        }
+
+        out.extend_from_slice(&peer_id.to_be_bytes());
+        if let Some(sent_time) = self.sent_time {
+            out.extend_from_slice(&sent_time.to_be_bytes());
+        }
+    }
+}
+
+/// A complete ENet datagram.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Datagram<'a> {
+    /// The header of the datagram.
+    pub header: Header,
+    /// The checksum following the header, if the hosts use a `Checksum`, as raw bytes, since ENet writes it in the
+    /// byte order of the sending machine (e.g. `u32::from_le_bytes` for a sender on x86).
+    pub checksum: Option<[u8; 4]>,
+    /// The commands in the datagram.
+    pub commands: Vec<Command<'a>>,
+}
+
+impl<'a> Datagram<'a> {
+    /// Parses a datagram sent by a host without a checksum.
+    pub fn parse(data: &'a [u8]) -> Result<Datagram<'a>, ParseError> {
+        Datagram::parse_inner(data, false)
+    }
+
+    /// Parses a datagram sent by a host with a `Checksum`, which is not verified.
+    pub fn parse_with_checksum(data: &'a [u8]) -> Result<Datagram<'a>, ParseError> {
+        Datagram::parse_inner(data, true)
+    }
+
+    fn parse_inner(data: &'a [u8], checksum: bool) -> Result<Datagram<'a>, ParseError> {
+        let (header, header_len) = Header::parse(data)?;
+        let mut reader = Reader(&data[header_len..]);
+
+        let checksum = if checksum { Some(reader.array()?) } else { None };
+
+        if header.compressed {
+            return Err(ParseError::Compressed);
+        }
+
+        let mut commands = Vec::new();
+        while !reader.0.is_empty() {
+            commands.push(Command::parse(&mut reader)?);
+        }
+
+        Ok(Datagram {
+            header,
+            checksum,
+            commands,
+        })
+    }
+
+    /// Encodes the datagram.
+    ///
+    /// Panics if the data of a command is longer than 65535 bytes, which ENet cannot represent.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+
+        self.header.encode_into(&mut out);
+        if let Some(checksum) = self.checksum {
+            out.extend_from_slice(&checksum);
+        }
+        for command in &self.commands {
+            command.encode_into(&mut out);
+        }
+
+        out
+    }
+}
+
+/// A command in a datagram, consisting of the common command header and the command itself.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Command<'a> {
+    /// The channel the command belongs to, or 0xFF for commands that do not belong to a channel.
+    pub channel_id: u8,
+    /// The reliable sequence number of the command in its channel.
+    pub reliable_sequence_number: u16,
+    /// Whether the receiver has to acknowledge the command.
+    pub acknowledge: bool,
+    /// Whether the command is unsequenced, i.e. set for `CommandBody::SendUnsequenced`.
+    pub unsequenced: bool,
+    /// The command itself.
+    pub body: CommandBody<'a>,
+}
+
+impl<'a> Command<'a> {
+    /// Creates a command, which is flagged as unsequenced if `body` is a `CommandBody::SendUnsequenced`.
+    pub fn new(channel_id: u8, reliable_sequence_number: u16, acknowledge: bool, body: CommandBody<'a>) -> Self {
+        Command {
+            channel_id,
+            reliable_sequence_number,
+            acknowledge,
+            unsequenced: matches!(body, CommandBody::SendUnsequenced { .. }),
+            body,
+        }
+    }
+
+    /// Returns the encoded length of this command, including its data.
+    pub fn encoded_len(&self) -> usize {
+        let fixed_len = match self.body {
+            CommandBody::Acknowledge { .. } => 8,
+            CommandBody::Connect(_) => 48,
+            CommandBody::VerifyConnect(_) => 44,
+            CommandBody::Disconnect { .. } => 8,
+            CommandBody::Ping => 4,
+            CommandBody::SendReliable { .. } => 6,
+            CommandBody::SendUnreliable { .. } => 8,
+            CommandBody::SendFragment(_) => 24,
+            CommandBody::SendUnsequenced { .. } => 8,
+            CommandBody::BandwidthLimit { .. } => 12,
+            CommandBody::ThrottleConfigure { .. } => 16,
+            CommandBody::SendUnreliableFragment(_) => 24,
+        };
+
+        fixed_len + self.body.data().map_or(0, <[u8]>::len)
+    }
+
+    fn parse(reader: &mut Reader<'a>) -> Result<Command<'a>, ParseError> {
+        let command = reader.u8()?;
+        let channel_id = reader.u8()?;
+        let reliable_sequence_number = reader.u16()?;
+
+        let body = match command & COMMAND_MASK {
+            COMMAND_ACKNOWLEDGE => CommandBody::Acknowledge {
+                received_reliable_sequence_number: reader.u16()?,
+                received_sent_time: reader.u16()?,
+            },
+            COMMAND_CONNECT => CommandBody::Connect(Connect {
+                outgoing_peer_id: reader.u16()?,
+                incoming_session_id: reader.u8()?,
+                outgoing_session_id: reader.u8()?,
+                mtu: reader.u32()?,
+                window_size: reader.u32()?,
+                channel_count: reader.u32()?,
+                incoming_bandwidth: reader.u32()?,
+                outgoing_bandwidth: reader.u32()?,
+                packet_throttle_interval: reader.u32()?,
+                packet_throttle_acceleration: reader.u32()?,
+                packet_throttle_deceleration: reader.u32()?,
+                connect_id: reader.u32()?,
+                data: reader.u32()?,
+            }),
+            COMMAND_VERIFY_CONNECT => CommandBody::VerifyConnect(VerifyConnect {
+                outgoing_peer_id: reader.u16()?,
+                incoming_session_id: reader.u8()?,
+                outgoing_session_id: reader.u8()?,
+                mtu: reader.u32()?,
+                window_size: reader.u32()?,
+                channel_count: reader.u32()?,
+                incoming_bandwidth: reader.u32()?,
+                outgoing_bandwidth: reader.u32()?,
+                packet_throttle_interval: reader.u32()?,
+                packet_throttle_acceleration: reader.u32()?,
+                packet_throttle_deceleration: reader.u32()?,
+                connect_id: reader.u32()?,
+            }),
+            COMMAND_DISCONNECT => CommandBody::Disconnect { data: reader.u32()? },
+            COMMAND_PING => CommandBody::Ping,
+            COMMAND_SEND_RELIABLE => {
+                let data_len = reader.u16()?;
+                CommandBody::SendReliable {
+                    data: reader.bytes(data_len.into())?,
+                }
+            }
+            COMMAND_SEND_UNRELIABLE => {
+                let unreliable_sequence_number = reader.u16()?;
+                let data_len = reader.u16()?;
+                CommandBody::SendUnreliable {
+                    unreliable_sequence_number,
+                    data: reader.bytes(data_len.into())?,
+                }
+            }
+            COMMAND_SEND_FRAGMENT => CommandBody::SendFragment(Fragment::parse(reader)?),
+            COMMAND_SEND_UNSEQUENCED => {
+                let unsequenced_group = reader.u16()?;
+                let data_len = reader.u16()?;
+                CommandBody::SendUnsequenced {
+                    unsequenced_group,
+                    data: reader.bytes(data_len.into())?,
+                }
+            }
+            COMMAND_BANDWIDTH_LIMIT => CommandBody::BandwidthLimit {
+                incoming_bandwidth: reader.u32()?,
+                outgoing_bandwidth: reader.u32()?,
+            },
+            COMMAND_THROTTLE_CONFIGURE => CommandBody::ThrottleConfigure {
+                packet_throttle_interval: reader.u32()?,
+                packet_throttle_acceleration: reader.u32()?,
+                packet_throttle_deceleration: reader.u32()?,
+            },
+            COMMAND_SEND_UNRELIABLE_FRAGMENT => CommandBody::SendUnreliableFragment(Fragment::parse(reader)?),
+            unknown => return Err(ParseError::UnknownCommand(unknown)),
+        };
+
+        Ok(Command {
+            channel_id,
+            reliable_sequence_number,
+            acknowledge: command & COMMAND_FLAG_ACKNOWLEDGE != 0,
+            unsequenced: command & COMMAND_FLAG_UNSEQUENCED != 0,
+            body,
+        })
+    }
+
+    /// Appends the encoded command to `out`.
+    ///
+    /// Panics if the data of the command is longer than 65535 bytes, which ENet cannot represent.
+    pub fn encode_into(&self, out: &mut Vec<u8>) {
+        let mut command = self.body.command_number();
+        if self.acknowledge {
+            command |= COMMAND_FLAG_ACKNOWLEDGE;
+        }
+        if self.unsequenced {
+            command |= COMMAND_FLAG_UNSEQUENCED;
+        }
+
+        out.reserve(self.encoded_len());
+        out.push(command);
+        out.push(self.channel_id);
+        out.extend_from_slice(&self.reliable_sequence_number.to_be_bytes());
+
+        let mut writer = Writer(out);
+        match &self.body {
+            CommandBody::Acknowledge {
+                received_reliable_sequence_number,
+                received_sent_time,
+            } => {
+                writer.u16(*received_reliable_sequence_number);
+                writer.u16(*received_sent_time);
+            }
+            CommandBody::Connect(connect) => {
+                writer.u16(connect.outgoing_peer_id);
+                writer.u8(connect.incoming_session_id);
+                writer.u8(connect.outgoing_session_id);
+                for value in [
+                    connect.mtu,
+                    connect.window_size,
+                    connect.channel_count,
+                    connect.incoming_bandwidth,
+                    connect.outgoing_bandwidth,
+                    connect.packet_throttle_interval,
+                    connect.packet_throttle_acceleration,
+                    connect.packet_throttle_deceleration,
+                    connect.connect_id,
+                    connect.data,
+                ] {
+                    writer.u32(value);
+                }
+            }
+            CommandBody::VerifyConnect(verify) => {
+                writer.u16(verify.outgoing_peer_id);
+                writer.u8(verify.incoming_session_id);
+                writer.u8(verify.outgoing_session_id);
+                for value in [
+                    verify.mtu,
+                    verify.window_size,
+                    verify.channel_count,
+                    verify.incoming_bandwidth,
+                    verify.outgoing_bandwidth,
+                    verify.packet_throttle_interval,
+                    verify.packet_throttle_acceleration,
+                    verify.packet_throttle_deceleration,
+                    verify.connect_id,
+                ] {
+                    writer.u32(value);
+                }
+            }
+            CommandBody::Disconnect { data } => writer.u32(*data),
+            CommandBody::Ping => (),
+            CommandBody::SendReliable { data } => writer.data(data),
+            CommandBody::SendUnreliable {
+                unreliable_sequence_number,
+                data,
+            } => {
+                writer.u16(*unreliable_sequence_number);
+                writer.data(data);
+            }
+            CommandBody::SendFragment(fragment) | CommandBody::SendUnreliableFragment(fragment) => {
+                fragment.encode(&mut writer)
+            }
+            CommandBody::SendUnsequenced {
+                unsequenced_group,
+                data,
+            } => {
+                writer.u16(*unsequenced_group);
+                writer.data(data);
+            }
+            CommandBody::BandwidthLimit {
+                incoming_bandwidth,
+                outgoing_bandwidth,
+            } => {
+                writer.u32(*incoming_bandwidth);
+                writer.u32(*outgoing_bandwidth);
+            }
+            CommandBody::ThrottleConfigure {
+                packet_throttle_interval,
+                packet_throttle_acceleration,
+                packet_throttle_deceleration,
+            } => {
+                writer.u32(*packet_throttle_interval);
+                writer.u32(*packet_throttle_acceleration);
+                writer.u32(*packet_throttle_deceleration);
+            }
+        }
+    }
+}
+
+/// The command-specific part of a `Command`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum CommandBody<'a> {
+    /// Acknowledges a command that was flagged with `Command::acknowledge`.
+    Acknowledge {
+        /// The reliable sequence number of the acknowledged command.
+        received_reliable_sequence_number: u16,
+        /// The sent time of the datagram that contained the acknowledged command.
+        received_sent_time: u16,
+    },
+    /// Requests a connection.
+    Connect(Connect),
+    /// Accepts a connection request.
+    VerifyConnect(VerifyConnect),
+    /// Disconnects, with the user-specified data passed to `Peer::disconnect`.
+    Disconnect {
+        /// The user-specified data of the disconnection.
+        data: u32,
+    },
+    /// Keeps the connection alive, and measures the round trip time.
+    Ping,
+    /// A reliable packet.
+    SendReliable {
+        /// The data of the packet.
+        data: &'a [u8],
+    },
+    /// An unreliable but sequenced packet.
+    SendUnreliable {
+        /// The sequence number of the packet among the unreliable packets of its channel.
+        unreliable_sequence_number: u16,
+        /// The data of the packet.
+        data: &'a [u8],
+    },
+    /// A fragment of a packet that is larger than the MTU, sent reliably.
+    SendFragment(Fragment<'a>),
+    /// An unsequenced packet.
+    SendUnsequenced {
+        /// The unsequenced group of the packet, which is used to discard duplicates.
+        unsequenced_group: u16,
+        /// The data of the packet.
+        data: &'a [u8],
+    },
+    /// Announces the bandwidth limits of the sender, in bytes/second (0 means unlimited).
+    BandwidthLimit {
+        /// The downstream bandwidth of the sender.
+        incoming_bandwidth: u32,
+        /// The upstream bandwidth of the sender.
+        outgoing_bandwidth: u32,
+    },
+    /// Configures the throttle of unreliable packets,
+    /// see [`Peer::configure_throttling`](crate::Peer::configure_throttling).
+    ThrottleConfigure {
+        /// The interval in which the throttle is adjusted, in milliseconds.
+        packet_throttle_interval: u32,
+        /// The amount the throttle increases by when the connection is good.
+        packet_throttle_acceleration: u32,
+        /// The amount the throttle decreases by when the connection is bad.
+        packet_throttle_deceleration: u32,
+    },
+    /// A fragment of a packet that is larger than the MTU, sent unreliably (see `PacketMode::UnreliableFragment`).
+    SendUnreliableFragment(Fragment<'a>),
+}
+
+impl<'a> CommandBody<'a> {
+    /// Returns the packet data carried by this command, if it carries any.
+    pub fn data(&self) -> Option<&'a [u8]> {
+        match *self {
+            CommandBody::SendReliable { data }
+            | CommandBody::SendUnreliable { data, .. }
+            | CommandBody::SendUnsequenced { data, .. } => Some(data),
+            CommandBody::SendFragment(Fragment { data, .. })
+            | CommandBody::SendUnreliableFragment(Fragment { data, .. }) => Some(data),
+            _ => None,
+        }
+    }
+
+    fn command_number(&self) -> u8 {
+        match self {
+            CommandBody::Acknowledge { .. } => COMMAND_ACKNOWLEDGE,
+            CommandBody::Connect(_) => COMMAND_CONNECT,
+            CommandBody::VerifyConnect(_) => COMMAND_VERIFY_CONNECT,
+            CommandBody::Disconnect { .. } => COMMAND_DISCONNECT,
+            CommandBody::Ping => COMMAND_PING,
+            CommandBody::SendReliable { .. } => COMMAND_SEND_RELIABLE,
+            CommandBody::SendUnreliable { .. } => COMMAND_SEND_UNRELIABLE,
+            CommandBody::SendFragment(_) => COMMAND_SEND_FRAGMENT,
+            CommandBody::SendUnsequenced { .. } => COMMAND_SEND_UNSEQUENCED,
+            CommandBody::BandwidthLimit { .. } => COMMAND_BANDWIDTH_LIMIT,
+            CommandBody::ThrottleConfigure { .. } => COMMAND_THROTTLE_CONFIGURE,
+            CommandBody::SendUnreliableFragment(_) => COMMAND_SEND_UNRELIABLE_FRAGMENT,
+        }
+    }
+}
+
+/// A connection request.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[allow(missing_docs)]
+pub struct Connect {
+    /// The ID the sender assigned to the receiving peer, which the receiver puts into the header of its datagrams.
+    pub outgoing_peer_id: u16,
+    pub incoming_session_id: u8,
+    pub outgoing_session_id: u8,
+    pub mtu: u32,
+    pub window_size: u32,
+    pub channel_count: u32,
+    pub incoming_bandwidth: u32,
+    pub outgoing_bandwidth: u32,
+    pub packet_throttle_interval: u32,
+    pub packet_throttle_acceleration: u32,
+    pub packet_throttle_deceleration: u32,
+    /// The random ID of the connection, see `PeerId::connect_id`.
+    pub connect_id: u32,
+    /// The user-specified data passed to `Host::connect`.
+    pub data: u32,
+}
+
+/// The acceptance of a connection request, with the settings the connection was negotiated to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[allow(missing_docs)]
+pub struct VerifyConnect {
+    /// The ID the sender assigned to the receiving peer, which the receiver puts into the header of its datagrams.
+    pub outgoing_peer_id: u16,
+    pub incoming_session_id: u8,
+    pub outgoing_session_id: u8,
+    pub mtu: u32,
+    pub window_size: u32,
+    pub channel_count: u32,
+    pub incoming_bandwidth: u32,
+    pub outgoing_bandwidth: u32,
+    pub packet_throttle_interval: u32,
+    pub packet_throttle_acceleration: u32,
+    pub packet_throttle_deceleration: u32,
+    /// The random ID of the connection, as sent in the `Connect` command.
+    pub connect_id: u32,
+}
+
+/// A fragment of a packet that is larger than the MTU.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Fragment<'a> {
+    /// The sequence number of the first fragment, which identifies the packet.
+    pub start_sequence_number: u16,
+    /// The number of fragments of the packet.
+    pub fragment_count: u32,
+    /// The index of this fragment.
+    pub fragment_number: u32,
+    /// The length of the whole packet.
+    pub total_length: u32,
+    /// The offset of this fragment in the packet.
+    pub fragment_offset: u32,
+    /// The data of this fragment.
+    pub data: &'a [u8],
+}
+
+impl<'a> Fragment<'a> {
+    fn parse(reader: &mut Reader<'a>) -> Result<Fragment<'a>, ParseError> {
+        let start_sequence_number = reader.u16()?;
+        let data_len = reader.u16()?;
+
+        Ok(Fragment {
+            start_sequence_number,
+            fragment_count: reader.u32()?,
+            fragment_number: reader.u32()?,
+            total_length: reader.u32()?,
+            fragment_offset: reader.u32()?,
+            data: reader.bytes(data_len.into())?,
+        })
+    }
+
+    fn encode(&self, writer: &mut Writer<'_>) {
+        writer.u16(self.start_sequence_number);
+        writer.u16(data_len(self.data));
+        writer.u32(self.fragment_count);
+        writer.u32(self.fragment_number);
+        writer.u32(self.total_length);
+        writer.u32(self.fragment_offset);
+        writer.0.extend_from_slice(self.data);
+    }
+}
+
+fn data_len(data: &[u8]) -> u16 {
+    u16::try_from(data.len()).expect("command data is longer than 65535 bytes")
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], ParseError> {
+        if self.0.len() < len {
+            return Err(ParseError::Truncated);
+        }
+
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, ParseError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ParseError> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, ParseError> {
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], ParseError> {
+        Ok(self.bytes(N)?.try_into().expect("slice has the requested length"))
+    }
+}
+
+struct Writer<'a>(&'a mut Vec<u8>);
+
+impl<'a> Writer<'a> {
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    /// Writes the length of `data`, followed by `data`.
+    fn data(&mut self, data: &[u8]) {
+        self.u16(data_len(data));
+        self.0.extend_from_slice(data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use super::{Command, CommandBody, Connect, Datagram, Fragment, Header, ParseError, UNASSIGNED_PEER_ID};
+    use crate::{
+        tests::{localhost, service_until, ENET},
+        Checksum, ChecksumAlgorithm, InterceptVerdict,
+    };
+
+    /// Sums up all bytes, so the byte order of the checksum can be checked on any machine.
+    struct ByteSum;
+
+    impl ChecksumAlgorithm for ByteSum {
+        fn checksum(buffers: &[&[u8]]) -> u32 {
+            buffers.iter().flat_map(|b| b.iter()).map(|&b| b as u32).sum()
+        }
+    }
+
+    #[test]
+    fn test_roundtrip_and_sizes() {
+        let connect = Connect {
+            outgoing_peer_id: 0,
+            incoming_session_id: 0xFF,
+            outgoing_session_id: 0xFF,
+            mtu: 1392,
+            window_size: 32768,
+            channel_count: 2,
+            incoming_bandwidth: 0,
+            outgoing_bandwidth: 0,
+            packet_throttle_interval: 5000,
+            packet_throttle_acceleration: 2,
+            packet_throttle_deceleration: 2,
+            connect_id: 0xDEAD_BEEF,
+            data: 42,
+        };
+        let fragment = Fragment {
+            start_sequence_number: 3,
+            fragment_count: 2,
+            fragment_number: 1,
+            total_length: 10,
+            fragment_offset: 5,
+            data: b"world",
+        };
+
+        let commands = vec![
+            (Command::new(0xFF, 1, true, CommandBody::Connect(connect)), 48),
+            (Command::new(0xFF, 2, false, CommandBody::Ping), 4),
+            (Command::new(0, 3, true, CommandBody::SendReliable { data: b"hi" }), 8),
+            (
+                Command::new(1, 0, false, CommandBody::SendUnsequenced { unsequenced_group: 7, data: b"" }),
+                8,
+            ),
+            (Command::new(1, 3, true, CommandBody::SendFragment(fragment)), 29),
+            (Command::new(0xFF, 4, false, CommandBody::Disconnect { data: 9 }), 8),
+        ];
+
+        for (command, len) in &commands {
+            let mut encoded = Vec::new();
+            command.encode_into(&mut encoded);
+            assert_eq!(encoded.len(), *len, "{:?}", command);
+            assert_eq!(command.encoded_len(), *len);
+        }
+
+        let datagram = Datagram {
+            header: Header {
+                peer_id: UNASSIGNED_PEER_ID,
+                session_id: 3,
+                compressed: false,
+                sent_time: Some(0xABCD),
+            },
+            checksum: Some([0x12, 0x34, 0x56, 0x78]),
+            commands: commands.into_iter().map(|(command, _)| command).collect(),
+        };
+
+        let encoded = datagram.encode();
+        assert_eq!(&encoded[..8], &[0xBF, 0xFF, 0xAB, 0xCD, 0x12, 0x34, 0x56, 0x78]);
+        assert_eq!(Datagram::parse_with_checksum(&encoded).unwrap(), datagram);
+        assert!(Datagram::parse_with_checksum(&encoded).unwrap().commands[3].unsequenced);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        // a header without sent time, and a reliable packet that claims to be longer than it is
+        assert_eq!(Datagram::parse(&[0x00, 0x01, 0x86, 0x00, 0x00, 0x01, 0x00, 0x05, b'h']), Err(ParseError::Truncated));
+        assert_eq!(Datagram::parse(&[0x00, 0x01, 0x0D, 0x00, 0x00, 0x01]), Err(ParseError::UnknownCommand(13)));
+        assert_eq!(Datagram::parse(&[0x40, 0x01, 0x12, 0x34]), Err(ParseError::Compressed));
+
+        let (header, len) = Header::parse(&[0x40, 0x01, 0x12, 0x34]).unwrap();
+        assert_eq!(len, 2);
+        assert!(header.compressed);
+        assert_eq!(header.peer_id, 1);
+    }
+
+    #[test]
+    fn test_parse_intercepted_connect() {
+        let address = localhost(12380);
+        let checksum = Checksum::custom::<ByteSum>();
+        let mut server = ENET.host_builder().address(address).checksum(checksum).build::<()>().unwrap();
+        let mut client = ENET.host_builder().checksum(checksum).build::<()>().unwrap();
+
+        let datagrams = Arc::new(Mutex::new(Vec::new()));
+        let intercepted = datagrams.clone();
+        server.set_intercept(move |context| {
+            intercepted.lock().unwrap().push(context.data().to_vec());
+            InterceptVerdict::Drop
+        });
+
+        let peer = client.connect(&address, 2, 42).unwrap();
+        let data = service_until(Duration::from_secs(1), || {
+            client.service(0).unwrap();
+            server.service(5).unwrap();
+            datagrams.lock().unwrap().first().cloned()
+        })
+        .expect("receiving timed out");
+
+        let datagram = Datagram::parse_with_checksum(&data).unwrap();
+        assert_eq!(datagram.header.peer_id, UNASSIGNED_PEER_ID);
+        assert!(datagram.header.sent_time.is_some());
+
+        // the checksum is computed with the checksum field set to 0 for datagrams to unassigned peers
+        let (_, header_len) = Header::parse(&data).unwrap();
+        let mut zeroed = data.clone();
+        zeroed[header_len..header_len + 4].fill(0);
+        assert_eq!(datagram.checksum, Some(ByteSum::checksum(&[&zeroed]).to_ne_bytes()));
+
+        match &datagram.commands[..] {
+            [Command {
+                channel_id: 0xFF,
+                acknowledge: true,
+                body: CommandBody::Connect(connect),
+                ..
+            }] => {
+                assert_eq!(connect.channel_count, 2);
+                assert_eq!(connect.connect_id, peer.connect_id());
+                assert_eq!(connect.data, 42);
+            }
+            other => panic!("unexpected commands: {:?}", other),
+        }
+        assert_eq!(datagram.encode(), data);
+    }
+}
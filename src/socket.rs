@@ -1,40 +1,277 @@
-use citizen_enet_sys::ENetBuffer;
-use std::{marker::PhantomData, ffi::c_void};
-
-use citizen_enet_sys::{enet_socket_send, ENetSocket};
-
-use crate::{Address, Error};
-
-// TODO: documentation
-// TODO: lifetimes
-#[derive(Clone, Debug)]
-pub struct Socket<'a, T: 'a> {
-    inner: ENetSocket,
-
-    _data: PhantomData<&'a mut T>,
-}
-
-impl<'a, T> Socket<'a, T> {
-    pub(crate) fn new(inner: ENetSocket) -> Self {
-        Self {
-            inner,
-            _data: PhantomData,
-        }
-    }
-
-    pub fn send_data(&mut self, addr: &Address, data: &[u8]) -> Result<u32, Error> {
-        let bytes_sent = unsafe {
-            let buffer = ENetBuffer {
-                data: data.as_ptr() as *mut c_void,
-                dataLength: data.len(),
-            };
-            enet_socket_send(self.inner, &addr.enet_address(), &buffer as *const _, 1)
-        };
-
-        if bytes_sent < 0 {
-            Err(Error(bytes_sent))
-        } else {
-            Ok(bytes_sent as u32)
-        }
-    }
-}
\ No newline at end of file
+use std::{ffi::c_void, io, marker::PhantomData, mem::ManuallyDrop};
+
+#[cfg(unix)]
+use std::os::unix::io::FromRawFd;
+#[cfg(windows)]
+use std::os::windows::io::FromRawSocket;
+
+use log::debug;
+
+use citizen_enet_sys::{
+    enet_socket_get_address, enet_socket_receive, enet_socket_send, enet_socket_set_option, ENetAddress,
+    ENetBuffer, ENetSocket, ENetSocketOption, _ENetSocketOption_ENET_SOCKOPT_BROADCAST,
+    _ENetSocketOption_ENET_SOCKOPT_NONBLOCK, _ENetSocketOption_ENET_SOCKOPT_RCVBUF,
+    _ENetSocketOption_ENET_SOCKOPT_SNDBUF,
+};
+
+use crate::{
+    capture::{Capture, SharedCapture},
+    conditioner::{Conditioner, SharedConditioner},
+    Address, Error,
+};
+
+/// The UDP socket of a `Host`, as returned by `Host::socket`.
+///
+/// ENet's sockets are dual-stack IPv6 sockets, so IPv4 addresses are handled as IPv4-mapped IPv6 addresses.
+#[derive(Clone, Debug)]
+pub struct Socket<'a, T: 'a> {
+    inner: ENetSocket,
+    hooks: SocketHooks,
+
+    _data: PhantomData<&'a mut T>,
+}
+
+/// The tools attached to a `Host` that see the datagrams sent through its `Socket`.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct SocketHooks {
+    pub(crate) conditioner: Option<SharedConditioner>,
+    pub(crate) capture: Option<SharedCapture>,
+}
+
+/// Sends the concatenation of `buffers` as a single datagram to `addr` through `socket`,
+/// bypassing any `NetworkConditioner`.
+pub(crate) fn send_raw(socket: ENetSocket, addr: &Address, buffers: &[&[u8]]) -> Result<u32, Error> {
+    let buffers: Vec<ENetBuffer> = buffers
+        .iter()
+        .map(|data| ENetBuffer {
+            data: data.as_ptr() as *mut c_void,
+            dataLength: data.len(),
+        })
+        .collect();
+
+    let bytes_sent = unsafe { enet_socket_send(socket, &addr.enet_address(), buffers.as_ptr(), buffers.len()) };
+
+    if bytes_sent < 0 {
+        Err(Error(bytes_sent))
+    } else {
+        Ok(bytes_sent as u32)
+    }
+}
+
+impl<'a, T> Socket<'a, T> {
+    pub(crate) fn new(inner: ENetSocket, hooks: SocketHooks) -> Self {
+        Self {
+            inner,
+            hooks,
+            _data: PhantomData,
+        }
+    }
+
+    /// Sends `data` as a single datagram to `addr`, returning the number of bytes sent.
+    pub fn send_data(&mut self, addr: &Address, data: &[u8]) -> Result<u32, Error> {
+        self.send_vectored(addr, &[data])
+    }
+
+    /// Sends the concatenation of `buffers` as a single datagram to `addr`, returning the number of bytes sent.
+    ///
+    /// If a `NetworkConditioner` is attached to the `Host`, the datagram goes through it,
+    /// and if the `Host` is being captured, the datagram is recorded.
+    pub fn send_vectored(&mut self, addr: &Address, buffers: &[&[u8]]) -> Result<u32, Error> {
+        let sent = match &self.hooks.conditioner {
+            Some(conditioner) => Conditioner::lock(conditioner).send(self.inner, addr, buffers)?,
+            None => send_raw(self.inner, addr, buffers)?,
+        };
+
+        if let Some(capture) = &self.hooks.capture {
+            Capture::lock(capture).record_sent(*addr, buffers);
+        }
+
+        Ok(sent)
+    }
+
+    /// Receives a single datagram into `buffer`, returning its sender and length,
+    /// or `None` if no datagram is available and the socket is non-blocking (the default).
+    ///
+    /// Datagrams received this way are not seen by ENet, so this is mostly useful for
+    /// hosts that are not serviced, or for datagrams that ENet would not understand anyway.
+    pub fn receive(&mut self, buffer: &mut [u8]) -> Result<Option<(Address, usize)>, Error> {
+        let mut address = ENetAddress {
+            host: unsafe { std::mem::zeroed() },
+            port: 0,
+            sin6_scope_id: 0,
+        };
+        let mut enet_buffer = ENetBuffer {
+            data: buffer.as_mut_ptr() as *mut c_void,
+            dataLength: buffer.len(),
+        };
+
+        let res = unsafe { enet_socket_receive(self.inner, &mut address, &mut enet_buffer, 1) };
+
+        match res {
+            0 => Ok(None),
+            // ENet also returns an error if the datagram was truncated
+            r if r < 0 => Err(Error(r)),
+            r => Ok(Some((Address::from_enet_address(&address), r as usize))),
+        }
+    }
+
+    /// Returns the address this socket is bound to, e.g. to find out the port assigned to a `Host` bound to port 0.
+    pub fn local_addr(&self) -> io::Result<Address> {
+        let mut address = ENetAddress {
+            host: unsafe { std::mem::zeroed() },
+            port: 0,
+            sin6_scope_id: 0,
+        };
+
+        if unsafe { enet_socket_get_address(self.inner, &mut address) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Address::from_enet_address(&address))
+    }
+
+    /// Sets whether operations on this socket are non-blocking.
+    ///
+    /// ENet makes its sockets non-blocking and relies on that when servicing a `Host`.
+    pub fn set_nonblocking(&mut self, nonblocking: bool) -> io::Result<()> {
+        self.set_option(_ENetSocketOption_ENET_SOCKOPT_NONBLOCK, nonblocking as i32)
+    }
+
+    /// Sets whether datagrams may be sent to broadcast addresses.
+    pub fn set_broadcast(&mut self, broadcast: bool) -> io::Result<()> {
+        self.set_option(_ENetSocketOption_ENET_SOCKOPT_BROADCAST, broadcast as i32)
+    }
+
+    /// Sets the size of the receive buffer of this socket, in bytes (`SO_RCVBUF`).
+    ///
+    /// The operating system may round or clamp the size, see `Socket::receive_buffer_size`.
+    pub fn set_receive_buffer_size(&mut self, size: usize) -> io::Result<()> {
+        self.set_option(_ENetSocketOption_ENET_SOCKOPT_RCVBUF, size.min(i32::MAX as usize) as i32)
+    }
+
+    /// Returns the actual size of the receive buffer of this socket, in bytes.
+    pub fn receive_buffer_size(&self) -> io::Result<usize> {
+        self.as_socket2().recv_buffer_size()
+    }
+
+    /// Sets the size of the send buffer of this socket, in bytes (`SO_SNDBUF`).
+    ///
+    /// The operating system may round or clamp the size, see `Socket::send_buffer_size`.
+    pub fn set_send_buffer_size(&mut self, size: usize) -> io::Result<()> {
+        self.set_option(_ENetSocketOption_ENET_SOCKOPT_SNDBUF, size.min(i32::MAX as usize) as i32)
+    }
+
+    /// Returns the actual size of the send buffer of this socket, in bytes.
+    pub fn send_buffer_size(&self) -> io::Result<usize> {
+        self.as_socket2().send_buffer_size()
+    }
+
+    /// Sets the time-to-live (IPv6 hop limit) of datagrams sent through this socket.
+    ///
+    /// This is also set for datagrams to IPv4 addresses where the platform supports it.
+    pub fn set_ttl(&mut self, ttl: u32) -> io::Result<()> {
+        let socket = self.as_socket2();
+
+        socket.set_unicast_hops_v6(ttl)?;
+        if let Err(err) = socket.set_ttl(ttl) {
+            debug!("could not set the IPv4 TTL of a dual-stack socket: {}", err);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the time-to-live (IPv6 hop limit) of datagrams sent through this socket.
+    pub fn ttl(&self) -> io::Result<u32> {
+        self.as_socket2().unicast_hops_v6()
+    }
+
+    /// Sets the type-of-service byte (DSCP and ECN) of datagrams sent through this socket,
+    /// e.g. `46 << 2` for DSCP "expedited forwarding".
+    ///
+    /// This sets the IPv6 traffic class where the platform supports it, and the IPv4 type of service
+    /// for datagrams to IPv4 addresses.
+    pub fn set_tos(&mut self, tos: u32) -> io::Result<()> {
+        let socket = self.as_socket2();
+
+        #[cfg(any(
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "linux",
+            target_os = "macos",
+            target_os = "netbsd",
+            target_os = "openbsd"
+        ))]
+        {
+            socket.set_tclass_v6(tos)?;
+            if let Err(err) = socket.set_tos(tos) {
+                debug!("could not set the IPv4 TOS of a dual-stack socket: {}", err);
+            }
+
+            Ok(())
+        }
+
+        #[cfg(not(any(
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "linux",
+            target_os = "macos",
+            target_os = "netbsd",
+            target_os = "openbsd"
+        )))]
+        socket.set_tos(tos)
+    }
+
+    fn set_option(&mut self, option: ENetSocketOption, value: i32) -> io::Result<()> {
+        if unsafe { enet_socket_set_option(self.inner, option, value) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    /// Borrows the socket as a `socket2::Socket`, for options ENet does not offer.
+    fn as_socket2(&self) -> ManuallyDrop<socket2::Socket> {
+        // the socket is still owned by ENet, so it must not be closed
+        #[cfg(unix)]
+        let socket = unsafe { socket2::Socket::from_raw_fd(self.inner) };
+        #[cfg(windows)]
+        let socket = unsafe { socket2::Socket::from_raw_socket(self.inner as _) };
+
+        ManuallyDrop::new(socket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, UdpSocket},
+        time::Duration,
+    };
+
+    use crate::tests::{localhost, service_until, ENET};
+
+    #[test]
+    fn test_local_addr_and_receive() {
+        let mut host = ENET.host_builder().address(localhost(0)).build::<()>().unwrap();
+        let mut socket = host.socket();
+
+        let local_addr = socket.local_addr().unwrap();
+        assert_ne!(local_addr.port(), 0);
+
+        socket.set_receive_buffer_size(1 << 16).unwrap();
+        assert!(socket.receive_buffer_size().unwrap() >= 1 << 16);
+        socket.set_ttl(32).unwrap();
+        assert_eq!(socket.ttl().unwrap(), 32);
+
+        let mut buffer = [0; 16];
+        assert_eq!(socket.receive(&mut buffer).unwrap(), None);
+
+        let sender = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        sender.send_to(b"raw", (Ipv4Addr::LOCALHOST, local_addr.port())).unwrap();
+
+        let (from, len) = service_until(Duration::from_secs(1), || socket.receive(&mut buffer).unwrap())
+            .expect("receiving timed out");
+        assert_eq!(from.port(), sender.local_addr().unwrap().port());
+        assert_eq!(&buffer[..len], b"raw");
+    }
+}